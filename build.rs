@@ -0,0 +1,37 @@
+use std::path::Path;
+use std::process::Command;
+
+fn main() {
+    println!(
+        "cargo:rustc-env=ACP_TARGET={}",
+        std::env::var("TARGET").unwrap_or_default()
+    );
+
+    // Source archives have no git metadata; the version then omits the commit.
+    let commit = Command::new("git")
+        .args(["rev-parse", "--short=12", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string());
+    if let Some(commit) = commit.filter(|commit| !commit.is_empty()) {
+        println!("cargo:rustc-env=ACP_GIT_COMMIT={}", commit);
+    }
+
+    // Rebuild when HEAD moves, without rerunning on every build when there is
+    // no .git directory.
+    let head = Path::new(".git/HEAD");
+    if head.exists() {
+        println!("cargo:rerun-if-changed=.git/HEAD");
+        if let Some(reference) = std::fs::read_to_string(head)
+            .ok()
+            .and_then(|head| head.strip_prefix("ref: ").map(|r| r.trim().to_string()))
+        {
+            let path = Path::new(".git").join(reference);
+            if path.exists() {
+                println!("cargo:rerun-if-changed={}", path.display());
+            }
+        }
+    }
+    println!("cargo:rerun-if-changed=build.rs");
+}
@@ -0,0 +1,34 @@
+use std::time::Duration;
+
+const JITTER: f64 = 0.2;
+
+pub struct Backoff {
+    min: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl Backoff {
+    pub fn new(min: Duration, max: Duration) -> Self {
+        let max = max.max(min);
+        Self {
+            min,
+            max,
+            current: min,
+        }
+    }
+
+    /// Returns the next delay with +/-20% jitter and doubles the base delay up
+    /// to `max`.
+    pub fn next(&mut self) -> Duration {
+        let delay = self
+            .current
+            .mul_f64(1.0 + rand::random_range(-JITTER..=JITTER));
+        self.current = self.current.saturating_mul(2).min(self.max);
+        delay
+    }
+
+    pub fn reset(&mut self) {
+        self.current = self.min;
+    }
+}
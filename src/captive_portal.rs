@@ -1,95 +1,1523 @@
 use crate::error::{AppError, Result};
-use headless_chrome::Browser;
 use regex::Regex;
-use reqwest::StatusCode;
-
-pub async fn login(url: &str, username: &str, password: &str) -> Result<()> {
-    let browser = Browser::default().map_err(|e| AppError::Browser(e.to_string()))?;
-
-    let tab = browser
-        .new_tab()
-        .map_err(|e| AppError::Browser(e.to_string()))?;
-    tab.navigate_to(url)
-        .map_err(|e| AppError::Browser(e.to_string()))?;
-    tab.wait_until_navigated()
-        .map_err(|e| AppError::Browser(e.to_string()))?;
-
-    let username_selector = "#ft_un";
-    let password_selector = "#ft_pd";
-
-    let username_field = tab
-        .find_element(username_selector)
-        .map_err(|e| AppError::Browser(e.to_string()))?;
-    username_field
-        .click()
-        .map_err(|e| AppError::Browser(e.to_string()))?;
-    username_field
-        .type_into(username)
-        .map_err(|e| AppError::Browser(e.to_string()))?;
-
-    let password_field = tab
-        .find_element(password_selector)
-        .map_err(|e| AppError::Browser(e.to_string()))?;
-    password_field
-        .click()
-        .map_err(|e| AppError::Browser(e.to_string()))?;
-    password_field
-        .type_into(password)
-        .map_err(|e| AppError::Browser(e.to_string()))?;
-
-    let submit_selector = "button[type='submit']";
-    let submit_button = tab
-        .find_element(submit_selector)
-        .map_err(|e| AppError::Browser(e.to_string()))?;
-    submit_button
-        .click()
-        .map_err(|e| AppError::Browser(e.to_string()))?;
-    tab.wait_until_navigated()
-        .map_err(|e| AppError::Browser(e.to_string()))?;
-
-    tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
-
-    let success_selector = "h2";
-    let success_element = tab
-        .find_element(success_selector)
-        .map_err(|e| AppError::Browser(e.to_string()))?;
-    let success_text: String = success_element
-        .get_content()
-        .map_err(|e| AppError::Browser(e.to_string()))?;
-
-    if success_text.contains("Authentication Successful") {
-        println!("login successful");
+use reqwest::cookie::Jar;
+use reqwest::{header, Method, StatusCode, Url};
+use schemars::JsonSchema;
+use secrecy::{ExposeSecret, SecretString};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, LazyLock, OnceLock};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConnectivityProbe {
+    /// URL fetched to decide whether the network has internet access.
+    pub url: String,
+    /// HTTP status the URL returns when the network is online.
+    pub expected_status: u16,
+    /// Body the URL returns when online, for text-based probes.
+    pub expected_body: Option<String>,
+    /// Also probe over IPv6 in `acp status`; disable on networks without IPv6.
+    pub check_ipv6: bool,
+    /// Addresses of the probe host to try when its name can't be resolved, as
+    /// happens on portals that intercept DNS until you log in.
+    pub fallback_ips: Vec<IpAddr>,
+    /// Extra attempts when the probe request times out or its connection drops,
+    /// before the check counts as failed; a refused connection or unreachable
+    /// host fails right away.
+    pub retries: u32,
+    /// Seconds to wait before each of those extra attempts.
+    pub retry_delay_secs: f64,
+}
+
+impl Default for ConnectivityProbe {
+    fn default() -> Self {
+        Self {
+            url: "http://clients3.google.com/generate_204".to_string(),
+            expected_status: StatusCode::NO_CONTENT.as_u16(),
+            expected_body: None,
+            check_ipv6: true,
+            fallback_ips: Vec::new(),
+            retries: 2,
+            retry_delay_secs: 0.5,
+        }
+    }
+}
+
+impl ConnectivityProbe {
+    fn retry_delay(&self) -> Duration {
+        Duration::try_from_secs_f64(self.retry_delay_secs).unwrap_or_default()
+    }
+
+    fn matches(&self, status: StatusCode, body: &str) -> bool {
+        status.as_u16() == self.expected_status
+            && self
+                .expected_body
+                .as_deref()
+                .is_none_or(|expected| body.trim() == expected.trim())
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryPolicy {
+    /// Number of login attempts before giving up.
+    pub max_attempts: u32,
+    /// Seconds to wait before the second attempt.
+    pub base_delay_secs: f64,
+    /// Factor applied to the delay after every further attempt.
+    pub backoff_factor: f64,
+    /// Timeout in seconds for the connectivity check after each attempt;
+    /// defaults to `request_timeout_secs`.
+    pub verify_timeout_secs: Option<u64>,
+    /// Connectivity checks after each attempt before it counts as failed, for
+    /// portals that take a while to open the session.
+    pub verify_attempts: u32,
+    /// Seconds to wait before each of those connectivity checks.
+    pub verify_interval_secs: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay_secs: 2.0,
+            backoff_factor: 2.0,
+            verify_timeout_secs: None,
+            verify_attempts: 5,
+            verify_interval_secs: 1.0,
+        }
+    }
+}
+
+impl RetryPolicy {
+    fn delay_before(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(2) as i32;
+        Duration::try_from_secs_f64(self.base_delay_secs * self.backoff_factor.powi(exponent))
+            .unwrap_or_default()
+    }
+
+    fn verify_timeout(&self) -> Option<Duration> {
+        self.verify_timeout_secs.map(Duration::from_secs)
+    }
+
+    fn verify_interval(&self) -> Duration {
+        Duration::try_from_secs_f64(self.verify_interval_secs).unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum LoginMethod {
+    /// Use the login form's `method`, falling back to POST.
+    #[default]
+    Auto,
+    /// Always POST the credentials as a form body.
+    Post,
+    /// Always send the credentials URL-encoded in the query string.
+    Get,
+}
+
+impl LoginMethod {
+    pub fn resolve(self, form_method: &Method) -> Method {
+        match self {
+            LoginMethod::Auto => form_method.clone(),
+            LoginMethod::Post => Method::POST,
+            LoginMethod::Get => Method::GET,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoginFields {
+    /// Name of the form field that carries the username.
+    pub username: String,
+    /// Name of the form field that carries the password.
+    pub password: String,
+}
+
+impl Default for LoginFields {
+    fn default() -> Self {
+        Self {
+            username: "username".to_string(),
+            password: "password".to_string(),
+        }
+    }
+}
+
+/// A terms-of-service or "click to continue" page that some portals show
+/// before their login form. Without `link_text` or `link_url`, the page's
+/// first form, or else its first link, is followed.
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct PreAuthStep {
+    /// Text of the link or button that continues to the login form, matched
+    /// case-insensitively as part of it, e.g. "Accept".
+    pub link_text: Option<String>,
+    /// Part of the `href` of the link, or the `action` of the form, that
+    /// continues to the login form.
+    pub link_url: Option<String>,
+}
+
+/// Where a pre-auth page continues to.
+#[derive(Debug)]
+pub enum PreAuthTarget {
+    Link(Url),
+    /// A form submitted with its hidden fields, checkboxes and submit button.
+    Form {
+        action: Url,
+        method: Method,
+        fields: Vec<(String, String)>,
+    },
+}
+
+impl fmt::Display for PreAuthTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PreAuthTarget::Link(url) => write!(f, "follow the link to {}", url),
+            PreAuthTarget::Form { action, method, .. } => {
+                write!(f, "submit the form with {} to {}", method, action)
+            }
+        }
+    }
+}
+
+/// How the credentials are put into a portal's login form.
+#[derive(Debug, Clone, Default)]
+pub struct FormOptions {
+    pub method: LoginMethod,
+    pub fields: LoginFields,
+    pub pre_auth: Option<PreAuthStep>,
+}
+
+pub const DEFAULT_USER_AGENT: &str = concat!("auto-captive-portal/", env!("CARGO_PKG_VERSION"));
+
+// Captive portals live on the local network, so private ranges never go
+// through the proxy.
+const DEFAULT_PROXY_BYPASS: &str =
+    "localhost,127.0.0.1,::1,10.0.0.0/8,172.16.0.0/12,192.168.0.0/16,169.254.0.0/16,.local";
+
+#[derive(Clone)]
+pub struct HttpOptions {
+    pub timeout: Duration,
+    pub user_agent: String,
+    pub proxy_url: Option<String>,
+    pub proxy_bypass: Vec<String>,
+}
+
+fn build_proxy(proxy_url: &str, bypass: &[String]) -> Result<reqwest::Proxy> {
+    let proxy = reqwest::Proxy::all(proxy_url)
+        .map_err(|e| AppError::Config(format!("Invalid proxy_url {}: {}", proxy_url, e)))?;
+    let no_proxy = std::iter::once(DEFAULT_PROXY_BYPASS)
+        .chain(bypass.iter().map(String::as_str))
+        .collect::<Vec<_>>()
+        .join(",");
+    Ok(proxy.no_proxy(reqwest::NoProxy::from_string(&no_proxy)))
+}
+
+fn http_client_builder(http: &HttpOptions) -> Result<reqwest::ClientBuilder> {
+    let mut builder = reqwest::Client::builder()
+        .timeout(http.timeout)
+        .user_agent(http.user_agent.as_str());
+    if let Some(proxy_url) = &http.proxy_url {
+        builder = builder.proxy(build_proxy(proxy_url, &http.proxy_bypass)?);
+    }
+    Ok(builder)
+}
+
+// The probe never follows redirects: a redirect is how most portals announce
+// themselves, and the portal page may not even be reachable over TLS.
+fn probe_client_builder(http: &HttpOptions) -> Result<reqwest::ClientBuilder> {
+    Ok(http_client_builder(http)?.redirect(reqwest::redirect::Policy::none()))
+}
+
+pub fn build_http_client(http: &HttpOptions) -> Result<reqwest::Client> {
+    Ok(http_client_builder(http)?.build()?)
+}
+
+pub struct HttpClients {
+    pub probe: reqwest::Client,
+    pub portal: reqwest::Client,
+    pub accepts_invalid_certs: bool,
+}
+
+impl HttpClients {
+    pub fn new(http: &HttpOptions, allow_invalid_portal_certs: bool) -> Result<Self> {
+        // Portals often set a session cookie on the probe's redirect or the
+        // login page and reject a login that doesn't send it back, so both
+        // clients share one jar for as long as they are reused.
+        let cookies = Arc::new(Jar::default());
+        let probe = probe_client_builder(http)?
+            .cookie_provider(cookies.clone())
+            .build()?;
+        let portal = http_client_builder(http)?.cookie_provider(cookies);
+        let portal = if allow_invalid_portal_certs {
+            log::warn!(
+                "allow_invalid_portal_certs is enabled: TLS certificates of captive portal pages \
+                 are NOT verified, so a spoofed portal could capture your credentials"
+            );
+            portal.danger_accept_invalid_certs(true)
+        } else {
+            portal
+        }
+        .build()?;
+
+        Ok(Self {
+            probe,
+            portal,
+            accepts_invalid_certs: allow_invalid_portal_certs,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Connectivity {
+    pub v4: bool,
+    pub v6: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PortalKind {
+    JavascriptRedirect,
+    MetaRefresh,
+    HttpRedirect,
+    GenericForm,
+    BasicAuth,
+    DigestAuth,
+    /// Found by the `portal_url_pattern` from the config.
+    ConfiguredPattern,
+}
+
+impl fmt::Display for PortalKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            PortalKind::JavascriptRedirect => "JavaScript redirect (FortiGate-style)",
+            PortalKind::MetaRefresh => "meta refresh",
+            PortalKind::HttpRedirect => "HTTP redirect",
+            PortalKind::GenericForm => "generic login form",
+            PortalKind::BasicAuth => "HTTP Basic auth",
+            PortalKind::DigestAuth => "HTTP Digest auth",
+            PortalKind::ConfiguredPattern => "portal_url_pattern match",
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct Portal {
+    url: String,
+    kind: PortalKind,
+}
+
+pub struct LoginForm {
+    pub action: Url,
+    pub method: Method,
+}
+
+pub struct LoginPage {
+    /// Where the page was served from, after following redirects.
+    pub url: Url,
+    pub form: LoginForm,
+    pub hidden_fields: HashMap<String, String>,
+    pub layout: PageLayout,
+}
+
+/// A fingerprint of a login page's structure, to notice when a portal is
+/// redesigned.
+#[derive(Debug, Clone)]
+pub struct PageLayout {
+    /// SHA-256 of the page's tag and attribute names, ignoring text and
+    /// attribute values such as per-session tokens.
+    pub hash: String,
+    pub has_password_field: bool,
+}
+
+impl Portal {
+    pub fn new(url: impl Into<String>, kind: PortalKind) -> Self {
+        Self {
+            url: url.into(),
+            kind,
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn kind(&self) -> PortalKind {
+        self.kind
+    }
+}
+
+pub async fn login_with_retry(
+    portal: &Portal,
+    username: &str,
+    password: &SecretString,
+    probe: &ConnectivityProbe,
+    clients: &HttpClients,
+    policy: &RetryPolicy,
+    options: &FormOptions,
+) -> Result<()> {
+    let max_attempts = policy.max_attempts.max(1);
+    let mut attempt = 1;
+
+    loop {
+        log::info!("Login attempt {}/{}", attempt, max_attempts);
+        let url = portal.url();
+        let result = match portal.kind() {
+            PortalKind::BasicAuth => {
+                login_basic_auth(url, username, password, probe, clients, policy).await
+            }
+            PortalKind::DigestAuth => {
+                login_digest_auth(url, username, password, probe, clients, policy).await
+            }
+            _ => login(url, username, password, probe, clients, policy, options).await,
+        };
+
+        match result {
+            Ok(()) => return Ok(()),
+            // Retrying rejected credentials only brings an account lockout closer.
+            Err(e @ AppError::CredentialsRejected(_)) => return Err(e),
+            Err(e) if attempt >= max_attempts => {
+                return Err(AppError::RetriesExhausted {
+                    attempts: attempt,
+                    source: Box::new(e),
+                })
+            }
+            Err(e) => {
+                attempt += 1;
+                let delay = policy.delay_before(attempt);
+                log::warn!("Login attempt failed: {}; retrying in {:?}", e, delay);
+                tokio::time::sleep(delay).await;
+            }
+        }
+    }
+}
+
+fn portal_unreachable(url: impl fmt::Display, e: reqwest::Error) -> AppError {
+    AppError::PortalUnreachable {
+        url: url.to_string(),
+        message: e.to_string(),
+    }
+}
+
+/// Classifies a portal's error answer to a login request.
+fn login_error(url: &Url, status: StatusCode) -> AppError {
+    match status {
+        // A 511 answer to a login means the portal still wants authentication.
+        StatusCode::UNAUTHORIZED
+        | StatusCode::FORBIDDEN
+        | StatusCode::NETWORK_AUTHENTICATION_REQUIRED => {
+            AppError::CredentialsRejected(format!("the portal answered {}", status))
+        }
+        status if status.is_server_error() => AppError::PortalUnreachable {
+            url: url.to_string(),
+            message: format!("responded with {}", status),
+        },
+        status => AppError::LoginFailed(format!("Portal responded with {}", status)),
+    }
+}
+
+/// Fetches the login page at `url`. A page without a password field is taken
+/// as the pre-auth page of `pre_auth` when one is configured, and the link or
+/// form it continues with is followed to reach the login form.
+pub async fn fetch_login_page(
+    client: &reqwest::Client,
+    url: &str,
+    pre_auth: Option<&PreAuthStep>,
+) -> Result<LoginPage> {
+    let (page_url, html) = fetch_page(client.get(url), url).await?;
+    let page = parse_login_page(&html, &page_url);
+    let Some(step) = pre_auth.filter(|_| !page.layout.has_password_field) else {
+        return Ok(page);
+    };
+    let Some(target) = find_pre_auth_target(&html, &page_url, step) else {
+        log::warn!(
+            "No link or form on {} matches pre_auth_step; using the page as the login page",
+            page_url
+        );
+        return Ok(page);
+    };
+
+    log::info!("Pre-auth page at {}: going to {}", page_url, target);
+    let request = match &target {
+        PreAuthTarget::Link(url) => client.get(url.clone()),
+        PreAuthTarget::Form {
+            action,
+            method,
+            fields,
+        } if method == Method::GET => client.get(action.clone()).query(fields),
+        PreAuthTarget::Form { action, fields, .. } => client.post(action.clone()).form(fields),
+    };
+    let (page_url, html) = fetch_page(request, &page_url).await?;
+    Ok(parse_login_page(&html, &page_url))
+}
+
+/// Sends a request for a portal page and returns where it ended up after
+/// redirects, with its HTML.
+async fn fetch_page(
+    request: reqwest::RequestBuilder,
+    url: impl fmt::Display,
+) -> Result<(Url, String)> {
+    let page = request
+        .send_logged()
+        .await
+        .map_err(|e| portal_unreachable(url, e))?;
+    let page_url = page.url().clone();
+    // A 511 carries the portal's own login page (RFC 6585).
+    let page = match page.status() {
+        StatusCode::NETWORK_AUTHENTICATION_REQUIRED => page,
+        status if status.is_server_error() => return Err(login_error(&page_url, status)),
+        _ => page.error_for_status()?,
+    };
+    let html = page
+        .text()
+        .await
+        .map_err(|e| portal_unreachable(&page_url, e))?;
+    Ok((page_url, html))
+}
+
+/// Extracts what a login submits from a login page's HTML, without network
+/// access.
+pub fn parse_login_page(html: &str, page_url: &Url) -> LoginPage {
+    let form = extract_login_form(html, page_url).unwrap_or(LoginForm {
+        action: page_url.clone(),
+        method: Method::POST,
+    });
+    LoginPage {
+        url: page_url.clone(),
+        form,
+        hidden_fields: extract_hidden_fields(html),
+        layout: page_layout(html),
+    }
+}
+
+pub async fn login(
+    url: &str,
+    username: &str,
+    password: &SecretString,
+    probe: &ConnectivityProbe,
+    clients: &HttpClients,
+    policy: &RetryPolicy,
+    options: &FormOptions,
+) -> Result<()> {
+    let client = &clients.portal;
+    if clients.accepts_invalid_certs {
+        log::warn!(
+            "Logging in to {} without verifying its TLS certificate",
+            url
+        );
+    }
+    let LoginPage {
+        form,
+        hidden_fields: mut form_data,
+        ..
+    } = fetch_login_page(client, url, options.pre_auth.as_ref()).await?;
+    form_data.insert(options.fields.username.clone(), username.to_string());
+    form_data.remove(&options.fields.password);
+
+    let mut fields: Vec<(&str, &str)> = form_data
+        .iter()
+        .map(|(name, value)| (name.as_str(), value.as_str()))
+        .collect();
+    fields.push((&options.fields.password, password.expose_secret()));
+    let action = form.action.clone();
+    let request = if options.method.resolve(&form.method) == Method::GET {
+        client.get(form.action).query(&fields)
+    } else {
+        client.post(form.action).form(&fields)
+    };
+
+    let resp = request
+        .send_logged()
+        .await
+        .map_err(|e| portal_unreachable(&action, e))?;
+    if !resp.status().is_success() {
+        return Err(login_error(&action, resp.status()));
+    }
+    // Portals usually answer bad credentials with the login form again.
+    let shows_login_form = resp
+        .text()
+        .await
+        .is_ok_and(|html| page_layout(&html).has_password_field);
+
+    if await_connectivity(probe, &clients.probe, policy).await? {
+        log::info!("login successful");
         Ok(())
+    } else if shows_login_form {
+        Err(AppError::CredentialsRejected(
+            "the portal showed the login form again".to_string(),
+        ))
     } else {
-        Err(AppError::LoginFailed(
-            "Authentication unsuccessful".to_string(),
+        Err(AppError::VerificationFailed(
+            "submitting the login form".to_string(),
         ))
     }
 }
 
+pub async fn login_basic_auth(
+    url: &str,
+    username: &str,
+    password: &SecretString,
+    probe: &ConnectivityProbe,
+    clients: &HttpClients,
+    policy: &RetryPolicy,
+) -> Result<()> {
+    if clients.accepts_invalid_certs {
+        log::warn!(
+            "Logging in to {} without verifying its TLS certificate",
+            url
+        );
+    }
+    let resp = clients
+        .portal
+        .get(url)
+        .basic_auth(username, Some(password.expose_secret()))
+        .send_logged()
+        .await
+        .map_err(|e| portal_unreachable(url, e))?;
+
+    if !resp.status().is_success() {
+        return Err(login_error(resp.url(), resp.status()));
+    }
+
+    if await_connectivity(probe, &clients.probe, policy).await? {
+        log::info!("login successful");
+        Ok(())
+    } else {
+        Err(AppError::VerificationFailed("Basic auth login".to_string()))
+    }
+}
+
+pub async fn login_digest_auth(
+    url: &str,
+    username: &str,
+    password: &SecretString,
+    probe: &ConnectivityProbe,
+    clients: &HttpClients,
+    policy: &RetryPolicy,
+) -> Result<()> {
+    if clients.accepts_invalid_certs {
+        log::warn!(
+            "Logging in to {} without verifying its TLS certificate",
+            url
+        );
+    }
+    // Every attempt needs a fresh nonce, so fetch the challenge again.
+    let challenge = clients
+        .portal
+        .get(url)
+        .send_logged()
+        .await
+        .map_err(|e| portal_unreachable(url, e))?;
+    let mut prompt = auth_challenge(&challenge, "digest")
+        .ok_or_else(|| AppError::LoginFailed("Portal no longer asks for Digest auth".to_string()))
+        .and_then(|value| {
+            digest_auth::parse(value)
+                .map_err(|e| AppError::LoginFailed(format!("Invalid Digest challenge: {}", e)))
+        })?;
+
+    let target = challenge.url().clone();
+    let uri = match target.query() {
+        Some(query) => format!("{}?{}", target.path(), query),
+        None => target.path().to_string(),
+    };
+    let context = digest_auth::AuthContext::new(username, password.expose_secret(), uri);
+    let authorization = prompt
+        .respond(&context)
+        .map_err(|e| AppError::LoginFailed(format!("Digest auth failed: {}", e)))?;
+
+    let resp = clients
+        .portal
+        .get(target)
+        .header(header::AUTHORIZATION, authorization.to_header_string())
+        .send_logged()
+        .await
+        .map_err(|e| portal_unreachable(url, e))?;
+
+    if !resp.status().is_success() {
+        return Err(login_error(resp.url(), resp.status()));
+    }
+
+    if await_connectivity(probe, &clients.probe, policy).await? {
+        log::info!("login successful");
+        Ok(())
+    } else {
+        Err(AppError::VerificationFailed(
+            "Digest auth login".to_string(),
+        ))
+    }
+}
+
+// Portals often need a moment after the form is accepted before traffic flows.
+async fn await_connectivity(
+    probe: &ConnectivityProbe,
+    client: &reqwest::Client,
+    policy: &RetryPolicy,
+) -> Result<bool> {
+    let checks = policy.verify_attempts.max(1);
+    for check in 1..=checks {
+        tokio::time::sleep(policy.verify_interval()).await;
+        match verify_internet_connectivity(probe, client, policy.verify_timeout()).await {
+            Ok(true) => return Ok(true),
+            Ok(false) => log::debug!("No internet access yet (check {}/{})", check, checks),
+            Err(e) if check < checks => {
+                log::debug!("Connectivity check {}/{} failed: {}", check, checks, e)
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(false)
+}
+
+/// `send` that logs each round trip at debug level: method, URL, the
+/// server's address, status and time taken, for bug reports about logins.
+pub(crate) trait SendLogged {
+    async fn send_logged(self) -> reqwest::Result<reqwest::Response>;
+}
+
+impl SendLogged for reqwest::RequestBuilder {
+    async fn send_logged(self) -> reqwest::Result<reqwest::Response> {
+        let (client, request) = self.build_split();
+        let request = request?;
+        let method = request.method().clone();
+        let url = loggable_url(request.url());
+        let started = Instant::now();
+        let result = client.execute(request).await;
+        let elapsed = started.elapsed().as_millis();
+
+        match &result {
+            Ok(resp) => {
+                let final_url = loggable_url(resp.url());
+                log::debug!(
+                    "{} {} -> {} from {} in {} ms{}",
+                    method,
+                    url,
+                    resp.status(),
+                    resp.remote_addr()
+                        .map_or("an unknown address".to_string(), |addr| addr.to_string()),
+                    elapsed,
+                    if final_url == url {
+                        String::new()
+                    } else {
+                        format!(" (redirected to {})", final_url)
+                    }
+                );
+            }
+            Err(e) => {
+                let mut message = e.to_string();
+                if let Some(failed_url) = e.url() {
+                    message = message.replace(failed_url.as_str(), &loggable_url(failed_url));
+                }
+                log::debug!(
+                    "{} {} failed after {} ms: {}",
+                    method,
+                    url,
+                    elapsed,
+                    message
+                );
+            }
+        }
+        result
+    }
+}
+
+/// `url` without its query, fragment and password: the query carries the
+/// magic value, and the credentials too when a login form is sent with GET.
+fn loggable_url(url: &Url) -> String {
+    let mut url = url.clone();
+    let had_query = url.query().is_some_and(|query| !query.is_empty());
+    url.set_query(None);
+    url.set_fragment(None);
+    let _ = url.set_password(None);
+    if had_query {
+        format!("{}?<redacted>", url)
+    } else {
+        url.to_string()
+    }
+}
+
+/// [`loggable_url`] for a portal URL as stored, for hook and notification
+/// payloads that leave the machine.
+pub fn shareable_portal_url(portal_url: &str) -> String {
+    match Url::parse(portal_url) {
+        Ok(url) => loggable_url(&url),
+        Err(_) => portal_url
+            .split(['?', '#'])
+            .next()
+            .unwrap_or_default()
+            .to_string(),
+    }
+}
+
+pub fn default_logout_url(portal_url: &str) -> Option<Url> {
+    Url::parse(portal_url).ok()?.join("/logout?").ok()
+}
+
+pub fn magic_from_portal_url(portal_url: &str) -> Option<String> {
+    let configured = DETECTION_PATTERNS
+        .get()
+        .and_then(|patterns| patterns.magic_value.as_ref())
+        .and_then(|magic_re| magic_re.captures(portal_url)?.name("magic"));
+    if let Some(magic) = configured {
+        return Some(magic.as_str().to_string());
+    }
+
+    Url::parse(portal_url)
+        .ok()?
+        .query()
+        .filter(|query| !query.is_empty())
+        .map(str::to_string)
+}
+
+pub async fn logout(client: &reqwest::Client, logout_url: &str, magic: Option<&str>) -> Result<()> {
+    let mut form_data = HashMap::new();
+    if let Some(magic) = magic {
+        form_data.insert("magic", magic);
+    }
+
+    client
+        .post(logout_url)
+        .form(&form_data)
+        .send_logged()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+pub async fn verify_internet_connectivity(
+    probe: &ConnectivityProbe,
+    client: &reqwest::Client,
+    timeout: Option<Duration>,
+) -> Result<bool> {
+    let mut request = client.get(&probe.url);
+    if let Some(timeout) = timeout {
+        request = request.timeout(timeout);
+    }
+    let resp = request.send_logged().await?;
+    let status = resp.status();
+    let body = resp.text().await?;
+    Ok(probe.matches(status, &body))
+}
+
+async fn probe_address_family(
+    probe: &ConnectivityProbe,
+    http: &HttpOptions,
+    host: &str,
+    addrs: &[SocketAddr],
+) -> bool {
+    if addrs.is_empty() {
+        return false;
+    }
+
+    let client = probe_client_builder(http)
+        .and_then(|builder| Ok(builder.resolve_to_addrs(host, addrs).build()?));
+    match client {
+        Ok(client) => verify_internet_connectivity(probe, &client, None)
+            .await
+            .unwrap_or(false),
+        Err(e) => {
+            log::warn!("Failed to build probe client for {}: {}", host, e);
+            false
+        }
+    }
+}
+
+fn probe_url(probe: &ConnectivityProbe) -> Result<Url> {
+    let url = Url::parse(&probe.url)
+        .map_err(|e| AppError::Config(format!("Invalid probe URL {}: {}", probe.url, e)))?;
+    if url.host_str().is_none() {
+        return Err(AppError::Config(format!(
+            "Probe URL {} has no host",
+            probe.url
+        )));
+    }
+    Ok(url)
+}
+
+pub async fn check_connectivity(
+    probe: &ConnectivityProbe,
+    http: &HttpOptions,
+) -> Result<Connectivity> {
+    let url = probe_url(probe)?;
+    let host = url.host_str().unwrap_or_default();
+    let port = url.port_or_known_default().unwrap_or(80);
+
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port))
+        .await
+        .map(Iterator::collect)
+        .unwrap_or_default();
+    let (v6_addrs, v4_addrs): (Vec<_>, Vec<_>) = addrs.into_iter().partition(SocketAddr::is_ipv6);
+    let v6_addrs = if probe.check_ipv6 {
+        v6_addrs
+    } else {
+        Vec::new()
+    };
+
+    let (v4, v6) = tokio::join!(
+        probe_address_family(probe, http, host, &v4_addrs),
+        probe_address_family(probe, http, host, &v6_addrs),
+    );
+    Ok(Connectivity { v4, v6 })
+}
+
+fn redirect_target(resp: &reqwest::Response) -> Option<Url> {
+    let location = resp.headers().get(header::LOCATION)?.to_str().ok()?;
+    resp.url().join(location.trim()).ok()
+}
+
+/// The `WWW-Authenticate` challenge for `scheme`, e.g. `basic` or `digest`.
+fn auth_challenge<'a>(resp: &'a reqwest::Response, scheme: &str) -> Option<&'a str> {
+    resp.headers()
+        .get_all(header::WWW_AUTHENTICATE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .find(|value| {
+            value
+                .trim_start()
+                .get(..scheme.len())
+                .is_some_and(|prefix| prefix.eq_ignore_ascii_case(scheme))
+        })
+}
+
+/// Extraction patterns from the config, tried before the built-in ones.
+#[derive(Debug, Default)]
+pub struct DetectionPatterns {
+    pub portal_url: Option<Regex>,
+    pub magic_value: Option<Regex>,
+}
+
+static DETECTION_PATTERNS: OnceLock<DetectionPatterns> = OnceLock::new();
+
+/// Uses `patterns` for every later extraction.
+pub fn init(patterns: DetectionPatterns) {
+    let _ = DETECTION_PATTERNS.set(patterns);
+}
+
+static PORTAL_URL_RE: LazyLock<Option<Regex>> =
+    LazyLock::new(|| compile_pattern(r#"window\.location="([^"]*)""#));
+// Tag patterns skip over quoted attribute values, which may contain '>'.
+static META_TAG_RE: LazyLock<Option<Regex>> =
+    LazyLock::new(|| compile_pattern(r#"(?i)<meta\b(?:[^>"']|"[^"]*"|'[^']*')*>"#));
+static INPUT_TAG_RE: LazyLock<Option<Regex>> =
+    LazyLock::new(|| compile_pattern(r#"(?i)<input\b(?:[^>"']|"[^"]*"|'[^']*')*>"#));
+static FORM_RE: LazyLock<Option<Regex>> =
+    LazyLock::new(|| compile_pattern(r#"(?is)<form\b((?:[^>"']|"[^"]*"|'[^']*')*)>(.*?)</form>"#));
+static PASSWORD_INPUT_RE: LazyLock<Option<Regex>> =
+    LazyLock::new(|| compile_pattern(r#"(?i)<input\b[^>]*\btype\s*=\s*["']?password\b"#));
+static LINK_RE: LazyLock<Option<Regex>> =
+    LazyLock::new(|| compile_pattern(r#"(?is)<a\b((?:[^>"']|"[^"]*"|'[^']*')*)>(.*?)</a>"#));
+static BUTTON_RE: LazyLock<Option<Regex>> = LazyLock::new(|| {
+    compile_pattern(r#"(?is)<button\b((?:[^>"']|"[^"]*"|'[^']*')*)>(.*?)</button>"#)
+});
+static TAG_RE: LazyLock<Option<Regex>> = LazyLock::new(|| {
+    compile_pattern(r#"<(/?[A-Za-z][-A-Za-z0-9]*)((?:[^>"']|"[^"]*"|'[^']*')*)>"#)
+});
+static ATTRIBUTE_RE: LazyLock<Option<Regex>> = LazyLock::new(|| {
+    compile_pattern(r#"([A-Za-z_:][-A-Za-z0-9_:.]*)\s*=\s*(?:"([^"]*)"|'([^']*)'|([^\s"'=<>`]+))"#)
+});
+
+fn compile_pattern(pattern: &str) -> Option<Regex> {
+    Regex::new(pattern)
+        .map_err(|e| log::error!("Invalid portal detection pattern {:?}: {}", pattern, e))
+        .ok()
+}
+
 pub fn extract_captive_portal_url(html: &str) -> Option<String> {
-    let re = Regex::new(r#"window\.location="([^"]*)""#).unwrap();
-    re.captures(html)
+    extract_configured_portal_url(html)
+        .or_else(|| extract_javascript_redirect_url(html))
+        .or_else(|| extract_meta_refresh_url(html))
+}
+
+/// The login URL the config's `portal_url_pattern` finds in `html`.
+pub fn extract_configured_portal_url(html: &str) -> Option<String> {
+    let portal_re = DETECTION_PATTERNS.get()?.portal_url.as_ref()?;
+    let url = portal_re.captures(html)?.name("url")?.as_str().trim();
+    (!url.is_empty()).then(|| url.to_string())
+}
+
+pub fn extract_javascript_redirect_url(html: &str) -> Option<String> {
+    PORTAL_URL_RE
+        .as_ref()?
+        .captures(html)
         .and_then(|cap| cap.get(1).map(|m| m.as_str().to_string()))
 }
 
-pub async fn check_captive_portal() -> Result<Option<String>> {
-    let url = "http://clients3.google.com/generate_204";
-    let client = reqwest::Client::new();
-    let resp = client.get(url).send().await?;
+pub fn extract_meta_refresh_url(html: &str) -> Option<String> {
+    META_TAG_RE.as_ref()?.find_iter(html).find_map(|tag| {
+        let attributes = parse_attributes(tag.as_str());
+        let is_refresh = attributes
+            .get("http-equiv")
+            .is_some_and(|value| value.eq_ignore_ascii_case("refresh"));
+        if !is_refresh {
+            return None;
+        }
+
+        let (_, target) = attributes.get("content")?.split_once(';')?;
+        let target = target.trim();
+        let url = target
+            .get(..3)
+            .filter(|prefix| prefix.eq_ignore_ascii_case("url"))
+            .and_then(|_| target[3..].trim_start().strip_prefix('='))?
+            .trim()
+            .trim_matches(|c| c == '\'' || c == '"');
+        (!url.is_empty()).then(|| url.to_string())
+    })
+}
+
+fn parse_attributes(tag: &str) -> HashMap<String, String> {
+    let Some(attribute_re) = ATTRIBUTE_RE.as_ref() else {
+        return HashMap::new();
+    };
+
+    attribute_re
+        .captures_iter(tag)
+        .filter_map(|cap| {
+            let value = cap.get(2).or(cap.get(3)).or(cap.get(4))?;
+            Some((cap[1].to_ascii_lowercase(), value.as_str().to_string()))
+        })
+        .collect()
+}
+
+/// The text a browser shows for some HTML, with tags removed and whitespace
+/// collapsed.
+fn visible_text(html: &str) -> String {
+    let text = match TAG_RE.as_ref() {
+        Some(tag_re) => tag_re.replace_all(html, " "),
+        None => html.into(),
+    };
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// The link or form on a pre-auth page that `step` continues with: the first
+/// one in the page whose text contains `link_text` and whose URL contains
+/// `link_url`. Without either, the first form wins over the first link.
+pub fn find_pre_auth_target(
+    html: &str,
+    page_url: &Url,
+    step: &PreAuthStep,
+) -> Option<PreAuthTarget> {
+    let wanted_text = step.link_text.as_deref().map(str::to_lowercase);
+    let text_matches = |text: &str| {
+        wanted_text
+            .as_ref()
+            .is_none_or(|wanted| text.to_lowercase().contains(wanted))
+    };
+    let url_matches = |url: &Url| {
+        step.link_url
+            .as_deref()
+            .is_none_or(|wanted| url.as_str().contains(wanted))
+    };
+
+    let mut candidates: Vec<(usize, PreAuthTarget)> = Vec::new();
+    for form in FORM_RE.as_ref()?.captures_iter(html) {
+        let attributes = parse_attributes(&form[1]);
+        let action = match attributes.get("action") {
+            Some(action) => match page_url.join(action.trim()) {
+                Ok(action) => action,
+                Err(_) => continue,
+            },
+            None => page_url.clone(),
+        };
+        let method = match attributes.get("method") {
+            Some(method) if method.eq_ignore_ascii_case("get") => Method::GET,
+            _ => Method::POST,
+        };
+        let Some((button, label)) = submit_buttons(&form[2])
+            .into_iter()
+            .find(|(_, label)| text_matches(label))
+            .or_else(|| text_matches(&visible_text(&form[2])).then(|| (None, String::new())))
+        else {
+            continue;
+        };
+        if !url_matches(&action) {
+            continue;
+        }
+        log::debug!("Pre-auth form to {} matches ({:?})", action, label);
+
+        let mut fields: Vec<(String, String)> =
+            extract_hidden_fields(&form[2]).into_iter().collect();
+        fields.extend(checkboxes(&form[2]));
+        fields.extend(button);
+        let offset = form.get(0).map_or(0, |m| m.start());
+        candidates.push((
+            offset,
+            PreAuthTarget::Form {
+                action,
+                method,
+                fields,
+            },
+        ));
+    }
+    let form_found = !candidates.is_empty();
+
+    for link in LINK_RE.as_ref()?.captures_iter(html) {
+        let Some(href) = parse_attributes(&link[1]).remove("href") else {
+            continue;
+        };
+        let Ok(url) = page_url.join(href.trim()) else {
+            continue;
+        };
+        if !matches!(url.scheme(), "http" | "https")
+            || !text_matches(&visible_text(&link[2]))
+            || !url_matches(&url)
+        {
+            continue;
+        }
+        let offset = link.get(0).map_or(0, |m| m.start());
+        candidates.push((offset, PreAuthTarget::Link(url)));
+    }
+
+    let unconstrained = step.link_text.is_none() && step.link_url.is_none();
+    if unconstrained && form_found {
+        candidates.retain(|(_, target)| matches!(target, PreAuthTarget::Form { .. }));
+    }
+    candidates.sort_by_key(|(offset, _)| *offset);
+    candidates.into_iter().next().map(|(_, target)| target)
+}
+
+/// Submit buttons in a form's HTML, with the field a click would send (for
+/// named buttons) and the label they show.
+fn submit_buttons(form_html: &str) -> Vec<(Option<(String, String)>, String)> {
+    let mut buttons = Vec::new();
+    if let Some(input_re) = INPUT_TAG_RE.as_ref() {
+        for tag in input_re.find_iter(form_html) {
+            let attributes = parse_attributes(tag.as_str());
+            let is_submit = attributes
+                .get("type")
+                .is_some_and(|kind| kind.eq_ignore_ascii_case("submit"));
+            if is_submit {
+                let value = attributes.get("value").cloned().unwrap_or_default();
+                let field = attributes
+                    .get("name")
+                    .map(|name| (name.clone(), value.clone()));
+                buttons.push((field, value));
+            }
+        }
+    }
+    if let Some(button_re) = BUTTON_RE.as_ref() {
+        for button in button_re.captures_iter(form_html) {
+            let attributes = parse_attributes(&button[1]);
+            let is_submit = attributes
+                .get("type")
+                .is_none_or(|kind| kind.eq_ignore_ascii_case("submit"));
+            if is_submit {
+                let field = attributes.get("name").map(|name| {
+                    (
+                        name.clone(),
+                        attributes.get("value").cloned().unwrap_or_default(),
+                    )
+                });
+                buttons.push((field, visible_text(&button[2])));
+            }
+        }
+    }
+    buttons
+}
+
+/// Every checkbox in a form's HTML as ticked, for the "I agree to the terms"
+/// box a pre-auth page may require.
+fn checkboxes(form_html: &str) -> Vec<(String, String)> {
+    let Some(input_re) = INPUT_TAG_RE.as_ref() else {
+        return Vec::new();
+    };
+    input_re
+        .find_iter(form_html)
+        .filter_map(|tag| {
+            let attributes = parse_attributes(tag.as_str());
+            let is_checkbox = attributes
+                .get("type")
+                .is_some_and(|kind| kind.eq_ignore_ascii_case("checkbox"));
+            let name = attributes.get("name").filter(|_| is_checkbox)?;
+            let value = attributes.get("value").map_or("on", String::as_str);
+            Some((name.clone(), value.to_string()))
+        })
+        .collect()
+}
+
+pub fn page_layout(html: &str) -> PageLayout {
+    let mut structure = String::new();
+    if let (Some(tag_re), Some(attribute_re)) = (TAG_RE.as_ref(), ATTRIBUTE_RE.as_ref()) {
+        for tag in tag_re.captures_iter(html) {
+            structure.push_str(&tag[1].to_ascii_lowercase());
+            for attribute in attribute_re.captures_iter(&tag[2]) {
+                structure.push(' ');
+                structure.push_str(&attribute[1].to_ascii_lowercase());
+            }
+            structure.push('\n');
+        }
+    }
+
+    PageLayout {
+        hash: format!("{:x}", Sha256::digest(structure.as_bytes())),
+        has_password_field: PASSWORD_INPUT_RE
+            .as_ref()
+            .is_some_and(|password_re| password_re.is_match(html)),
+    }
+}
+
+pub fn extract_hidden_fields(html: &str) -> HashMap<String, String> {
+    let mut fields = HashMap::new();
+    let Some(input_re) = INPUT_TAG_RE.as_ref() else {
+        return fields;
+    };
+
+    for tag in input_re.find_iter(html) {
+        let attributes = parse_attributes(tag.as_str());
+        let is_hidden = attributes
+            .get("type")
+            .is_some_and(|kind| kind.eq_ignore_ascii_case("hidden"));
+        if let (true, Some(name)) = (is_hidden, attributes.get("name")) {
+            let value = attributes.get("value").cloned().unwrap_or_default();
+            fields.insert(name.clone(), value);
+        }
+    }
+
+    fields
+}
+
+pub fn extract_login_form(html: &str, page_url: &Url) -> Option<LoginForm> {
+    let form_re = FORM_RE.as_ref()?;
+    let password_re = PASSWORD_INPUT_RE.as_ref()?;
+
+    let forms: Vec<_> = form_re.captures_iter(html).collect();
+    let form = forms
+        .iter()
+        .find(|cap| password_re.is_match(&cap[2]))
+        .or(forms.first())?;
+
+    let attributes = parse_attributes(&form[1]);
+    let action = match attributes.get("action") {
+        Some(action) => page_url.join(action.trim()).ok()?,
+        None => page_url.clone(),
+    };
+    let method = match attributes.get("method") {
+        Some(method) if method.eq_ignore_ascii_case("get") => Method::GET,
+        _ => Method::POST,
+    };
+
+    Some(LoginForm { action, method })
+}
+
+/// Like [`check_captive_portal`], but resolves the probe host first so a DNS
+/// failure is reported as [`AppError::DnsResolution`] rather than a generic
+/// network error, retrying via the probe's `fallback_ips` when there are any.
+/// Timeouts and dropped connections are retried as the probe's `retries` allow.
+pub async fn detect_captive_portal(
+    probe: &ConnectivityProbe,
+    http: &HttpOptions,
+    client: &reqwest::Client,
+) -> Result<Option<Portal>> {
+    let url = probe_url(probe)?;
+    let Some(host) = url.domain() else {
+        return check_with_retries(probe, client).await;
+    };
+    let port = url.port_or_known_default().unwrap_or(80);
+
+    let resolved = tokio::net::lookup_host((host, port))
+        .await
+        .is_ok_and(|mut addrs| addrs.next().is_some());
+    if resolved {
+        return check_with_retries(probe, client).await;
+    }
+
+    if probe.fallback_ips.is_empty() {
+        return Err(AppError::DnsResolution {
+            host: host.to_string(),
+        });
+    }
+    log::warn!(
+        "Could not resolve {}, the portal may be intercepting DNS; retrying the probe via {:?}",
+        host,
+        probe.fallback_ips
+    );
+    let addrs: Vec<SocketAddr> = probe
+        .fallback_ips
+        .iter()
+        .map(|ip| SocketAddr::new(*ip, port))
+        .collect();
+    let client = probe_client_builder(http)?
+        .resolve_to_addrs(host, &addrs)
+        .build()?;
+    check_with_retries(probe, &client).await
+}
+
+/// [`check_captive_portal`], retried up to the probe's `retries` times when
+/// the request failed in a way a congested network recovers from.
+async fn check_with_retries(
+    probe: &ConnectivityProbe,
+    client: &reqwest::Client,
+) -> Result<Option<Portal>> {
+    let mut retries_left = probe.retries;
+    loop {
+        match check_captive_portal(probe, client).await {
+            Err(e) if retries_left > 0 && is_transient(&e) => {
+                retries_left -= 1;
+                log::warn!(
+                    "Connectivity probe failed: {}; retrying in {:?}",
+                    e,
+                    probe.retry_delay()
+                );
+                tokio::time::sleep(probe.retry_delay()).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Whether a failed request may succeed when sent again: timeouts and dropped
+/// connections may, while a refused connection or an unreachable network
+/// won't change within a second.
+fn is_transient(error: &AppError) -> bool {
+    let AppError::Network(e) = error else {
+        return false;
+    };
+    if e.is_timeout() {
+        return true;
+    }
+
+    let mut source = std::error::Error::source(e);
+    while let Some(cause) = source {
+        if let Some(io_error) = cause.downcast_ref::<std::io::Error>() {
+            return matches!(
+                io_error.kind(),
+                std::io::ErrorKind::TimedOut
+                    | std::io::ErrorKind::ConnectionReset
+                    | std::io::ErrorKind::ConnectionAborted
+                    | std::io::ErrorKind::BrokenPipe
+                    | std::io::ErrorKind::UnexpectedEof
+                    | std::io::ErrorKind::Interrupted
+            );
+        }
+        source = cause.source();
+    }
+    false
+}
+
+/// Runs [`detect_captive_portal`] with every probe at once. The first probe
+/// to find the network online decides without waiting for slower ones;
+/// otherwise the earliest probe in the list that found a portal wins, and
+/// failing that the earliest one's error.
+pub async fn detect_with_probes(
+    probes: &[ConnectivityProbe],
+    http: &HttpOptions,
+    client: &reqwest::Client,
+) -> Result<Option<Portal>> {
+    if let [probe] = probes {
+        return detect_captive_portal(probe, http, client).await;
+    }
+
+    let mut tasks = tokio::task::JoinSet::new();
+    for (index, probe) in probes.iter().cloned().enumerate() {
+        let (http, client) = (http.clone(), client.clone());
+        tasks.spawn(async move { (index, detect_captive_portal(&probe, &http, &client).await) });
+    }
+
+    let mut portal: Option<(usize, Portal)> = None;
+    let mut error: Option<(usize, AppError)> = None;
+    // Returning drops the set, which aborts the probes still running.
+    while let Some(joined) = tasks.join_next().await {
+        let (index, result) = match joined {
+            Ok(joined) => joined,
+            Err(e) => (usize::MAX, Err(AppError::Io(e.into()))),
+        };
+        match result {
+            Ok(None) => return Ok(None),
+            Ok(Some(found)) if portal.as_ref().is_none_or(|(first, _)| index < *first) => {
+                portal = Some((index, found));
+            }
+            Err(e) if error.as_ref().is_none_or(|(first, _)| index < *first) => {
+                error = Some((index, e));
+            }
+            _ => {}
+        }
+    }
+    match (portal, error) {
+        (Some((_, portal)), _) => Ok(Some(portal)),
+        (None, Some((_, e))) => Err(e),
+        (None, None) => Err(AppError::Config("No connectivity probe configured".into())),
+    }
+}
+
+pub async fn check_captive_portal(
+    probe: &ConnectivityProbe,
+    client: &reqwest::Client,
+) -> Result<Option<Portal>> {
+    let resp = client.get(&probe.url).send_logged().await?;
 
     match resp.status() {
-        StatusCode::NO_CONTENT => Ok(None),
-        StatusCode::OK => {
+        // Digest wins when both are offered, since it never sends the password.
+        StatusCode::UNAUTHORIZED if auth_challenge(&resp, "digest").is_some() => Ok(Some(
+            Portal::new(resp.url().as_str(), PortalKind::DigestAuth),
+        )),
+        StatusCode::UNAUTHORIZED if auth_challenge(&resp, "basic").is_some() => Ok(Some(
+            Portal::new(resp.url().as_str(), PortalKind::BasicAuth),
+        )),
+        status if status.is_redirection() && redirect_target(&resp).is_some() => {
+            Ok(redirect_target(&resp).map(|url| Portal::new(url, PortalKind::HttpRedirect)))
+        }
+        // RFC 6585: the portal answers the probe itself, pointing at its login
+        // page in a Location header or in the body, or serving the form there.
+        StatusCode::NETWORK_AUTHENTICATION_REQUIRED => {
+            if let Some(url) = redirect_target(&resp) {
+                return Ok(Some(Portal::new(url, PortalKind::HttpRedirect)));
+            }
+            let final_url = resp.url().clone();
             let html = resp.text().await?;
-            let captive_portal_url = extract_captive_portal_url(&html);
-
-            if let Some(url) = captive_portal_url {
-                Ok(Some(url))
-            } else {
-                Ok(None)
+            Ok(Some(portal_in_page(&html, &final_url).unwrap_or_else(
+                || Portal::new(final_url, PortalKind::GenericForm),
+            )))
+        }
+        status if status.is_client_error() || status.is_server_error() => Err(AppError::Portal {
+            url: resp.url().to_string(),
+            status,
+        }),
+        status => {
+            let final_url = resp.url().clone();
+            let html = resp.text().await?;
+            if probe.matches(status, &html) {
+                return Ok(None);
             }
+            Ok(portal_in_page(&html, &final_url))
         }
-        _ => Err(AppError::Network(resp.error_for_status().unwrap_err())),
+    }
+}
+
+/// The portal a page announces with a JavaScript redirect or meta refresh,
+/// or the page itself when it holds a login form.
+fn portal_in_page(html: &str, page_url: &Url) -> Option<Portal> {
+    let detected = extract_configured_portal_url(html)
+        .map(|url| (url, PortalKind::ConfiguredPattern))
+        .or_else(|| {
+            extract_javascript_redirect_url(html).map(|url| (url, PortalKind::JavascriptRedirect))
+        })
+        .or_else(|| extract_meta_refresh_url(html).map(|url| (url, PortalKind::MetaRefresh)));
+    if let Some((url, kind)) = detected {
+        return page_url.join(&url).ok().map(|url| Portal::new(url, kind));
+    }
+
+    let has_login_form = PASSWORD_INPUT_RE
+        .as_ref()
+        .is_some_and(|password_re| password_re.is_match(html));
+    has_login_form.then(|| Portal::new(page_url.clone(), PortalKind::GenericForm))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_quoted_and_unquoted_attributes() {
+        let attributes =
+            parse_attributes(r#"<input type=hidden name='magic' value="0a1b2c" data-x = y>"#);
+        assert_eq!(attributes["type"], "hidden");
+        assert_eq!(attributes["name"], "magic");
+        assert_eq!(attributes["value"], "0a1b2c");
+        assert_eq!(attributes["data-x"], "y");
+    }
+
+    #[test]
+    fn lowercases_attribute_names_but_not_values() {
+        let attributes = parse_attributes(r#"<INPUT NAME="Magic" Value='AbC'>"#);
+        assert_eq!(attributes["name"], "Magic");
+        assert_eq!(attributes["value"], "AbC");
+    }
+
+    #[test]
+    fn extracts_hidden_fields_in_any_attribute_order() {
+        let fields = extract_hidden_fields(
+            r#"<form>
+                <input type="hidden" name="magic" value="0a1b2c">
+                <input value='4Tredir' name='redir' type='HIDDEN'>
+                <input value=1 type=hidden name=step>
+                <input type="hidden" name="empty">
+                <input type="text" name="username" value="ignored">
+            </form>"#,
+        );
+        assert_eq!(fields.len(), 4);
+        assert_eq!(fields["magic"], "0a1b2c");
+        assert_eq!(fields["redir"], "4Tredir");
+        assert_eq!(fields["step"], "1");
+        assert_eq!(fields["empty"], "");
+    }
+
+    #[test]
+    fn keeps_a_quoted_greater_than_inside_the_tag() {
+        let fields = extract_hidden_fields(
+            r#"<input type="hidden" value="a>b" name="token"><input type='hidden' name='next' value='x > y'>"#,
+        );
+        assert_eq!(fields["token"], "a>b");
+        assert_eq!(fields["next"], "x > y");
+    }
+
+    #[test]
+    fn extracts_meta_refresh_targets() {
+        assert_eq!(
+            extract_meta_refresh_url(
+                r#"<meta http-equiv="refresh" content="0;url=http://10.0.0.1:1000/fgtauth?0a1b">"#
+            )
+            .as_deref(),
+            Some("http://10.0.0.1:1000/fgtauth?0a1b")
+        );
+        assert_eq!(
+            extract_meta_refresh_url(
+                r#"<meta content="5; url='http://portal.example/login'" http-equiv="refresh">"#
+            )
+            .as_deref(),
+            Some("http://portal.example/login")
+        );
+        assert_eq!(
+            extract_meta_refresh_url(
+                r#"<META HTTP-EQUIV=Refresh CONTENT='0; URL = "/login?x=1"'>"#
+            )
+            .as_deref(),
+            Some("/login?x=1")
+        );
+    }
+
+    #[test]
+    fn ignores_meta_tags_without_a_refresh_url() {
+        assert_eq!(
+            extract_meta_refresh_url(r#"<meta http-equiv="refresh" content="30">"#),
+            None
+        );
+        assert_eq!(
+            extract_meta_refresh_url(r#"<meta http-equiv="refresh" content="0;url=">"#),
+            None
+        );
+        assert_eq!(
+            extract_meta_refresh_url(r#"<meta name="refresh" content="0;url=/login">"#),
+            None
+        );
+    }
+
+    #[test]
+    fn resolves_a_relative_meta_refresh_against_the_page() {
+        let page_url = Url::parse("http://10.0.0.1:1000/portal/index.html").unwrap();
+        let portal = portal_in_page(
+            r#"<html><head><meta http-equiv="refresh" content="0;url=login?0a1b"></head></html>"#,
+            &page_url,
+        )
+        .unwrap();
+        assert_eq!(portal.url(), "http://10.0.0.1:1000/portal/login?0a1b");
+        assert_eq!(portal.kind(), PortalKind::MetaRefresh);
+    }
+
+    #[test]
+    fn shareable_portal_urls_drop_the_magic_value() {
+        assert_eq!(
+            shareable_portal_url("http://10.0.0.1:1000/fgtauth?0a1b2c3d4e5f"),
+            "http://10.0.0.1:1000/fgtauth?<redacted>"
+        );
+        assert_eq!(
+            shareable_portal_url("https://portal.example/login"),
+            "https://portal.example/login"
+        );
+        assert_eq!(shareable_portal_url("/fgtauth?0a1b2c3d4e5f"), "/fgtauth");
     }
 }
@@ -0,0 +1,586 @@
+use log::LevelFilter;
+
+pub const USAGE: &str = "Usage: acp-script [OPTIONS] [COMMAND]
+
+Commands:
+  (none)                   Run the daemon, checking for captive portals in a loop
+  setup [--network SSID] [--no-start] [--system]
+                           Store credentials and install the background service;
+                           --no-start (or --install-only) enables it without starting it;
+                           --system installs it system-wide to run at boot (Linux, as root)
+  check [--dry-run]        Check for a portal once, log in if needed, then exit;
+                           --dry-run only shows what would be submitted
+  status [--json] [--watch [--interval SECS]]
+                           Show credentials, service and connectivity status; --watch
+                           redraws it every SECS seconds (default 2) until Ctrl-C
+  health [--json]          Run every self-check and suggest fixes for the failing ones
+  logs [-n N] [--follow]   Print the last N log lines (default 50), optionally following new ones
+  creds <ACTION> [--network SSID]
+                           Manage stored credentials without reinstalling the service:
+                           set (prompt for new ones), clear, or show-user
+  diagnose [-o FILE] [-n N]
+                           Write a bug-report bundle: the portal page, what each parser found
+                           in it, the last N log lines (default 200) and system info, with
+                           credentials and session tokens redacted; nothing is uploaded
+  test-portal <FILE> [--url URL]
+                           Show what would be submitted for a saved portal page, without
+                           network access; --url is the page's address, for relative links
+  logout                   Log out of the last captive portal
+  test-notification        Send a sample notification to check that notifications get through
+  restart                  Restart the background service, e.g. after editing the config
+  uninstall                Remove the service, credentials and state
+  version, --version       Print the version, target and git commit of this build
+  --print-config-schema    Print the JSON schema of the config file
+  --help                   Show this help
+
+Global options:
+  -v, --verbose            Show debug output on the console; -vv also shows trace output
+  -q, --quiet              Only show warnings and errors on the console
+                           (neither changes what is written to the log file)
+  --timeout SECS           Timeout for each HTTP request made by status, check, health and
+                           diagnose, instead of request_timeout_secs from the config file
+
+Exit codes:
+  0   Success; for check, logged in to a captive portal or inspected it with --dry-run
+  1   Any other failure, e.g. the state or log file could not be written
+  2   check only: no captive portal found, or login skipped on a network not in allowed_ssids
+  3   check only: already logged in to the portal on this Wi-Fi network
+  4   check only: offline, the connectivity probe's host could not be resolved
+  5   health only: one or more checks failed
+  10  Credentials missing, unreadable or rejected by the portal
+  11  Network error, the connectivity probe or portal returned an error status, or
+      there was no internet access after a login the portal accepted
+  12  Installing, removing or restarting the background service failed
+  13  Invalid configuration";
+
+const DEFAULT_LOG_LINES: usize = 50;
+const DEFAULT_DIAGNOSE_LOG_LINES: usize = 200;
+const DEFAULT_WATCH_INTERVAL_SECS: u64 = 2;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum CredsAction {
+    Set,
+    Clear,
+    ShowUser,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command {
+    Daemon,
+    Setup {
+        network: Option<String>,
+        start: bool,
+        system: bool,
+    },
+    Check {
+        dry_run: bool,
+    },
+    Status {
+        json: bool,
+        /// Seconds between redraws with `--watch`.
+        watch: Option<u64>,
+    },
+    Health {
+        json: bool,
+    },
+    Logs {
+        lines: usize,
+        follow: bool,
+    },
+    Creds {
+        action: CredsAction,
+        network: Option<String>,
+    },
+    Diagnose {
+        output: Option<String>,
+        lines: usize,
+    },
+    TestPortal {
+        file: String,
+        url: Option<String>,
+    },
+    Logout,
+    TestNotification,
+    Restart,
+    Uninstall,
+    #[cfg(target_os = "windows")]
+    Service,
+    PrintConfigSchema,
+    Version,
+    Help,
+}
+
+impl Command {
+    pub fn is_interactive(&self) -> bool {
+        matches!(
+            self,
+            Command::Setup { .. }
+                | Command::Uninstall
+                | Command::Restart
+                | Command::Status { .. }
+                | Command::Health { .. }
+                | Command::Diagnose { .. }
+                | Command::TestPortal { .. }
+                | Command::Logout
+                | Command::TestNotification
+        )
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Cli {
+    pub command: Command,
+    pub console_level: Option<LevelFilter>,
+    pub timeout: Option<u64>,
+}
+
+fn verbosity(arg: &str) -> Option<usize> {
+    match arg {
+        "--verbose" => Some(1),
+        _ => arg
+            .strip_prefix('-')
+            .filter(|flags| !flags.is_empty() && flags.chars().all(|c| c == 'v'))
+            .map(str::len),
+    }
+}
+
+fn value<'a>(args: &mut impl Iterator<Item = &'a str>, flag: &str) -> Result<&'a str, String> {
+    args.next()
+        .ok_or_else(|| format!("{} requires a value", flag))
+}
+
+fn unexpected(command: &str, arg: &str) -> String {
+    format!("Unexpected argument '{}' for {}", arg, command)
+}
+
+/// Parses the arguments after the program name.
+pub fn parse<S: AsRef<str>>(args: &[S]) -> Result<Cli, String> {
+    let mut level = 0;
+    let mut quiet = false;
+    let mut timeout = None;
+    let mut rest = Vec::new();
+    let mut args = args.iter().map(AsRef::as_ref);
+    while let Some(arg) = args.next() {
+        match (arg, verbosity(arg)) {
+            ("-q" | "--quiet", _) => quiet = true,
+            ("--timeout", _) => {
+                let secs = value(&mut args, arg)?;
+                timeout = match secs.parse() {
+                    Ok(0) | Err(_) => return Err(format!("Invalid timeout: {}", secs)),
+                    Ok(secs) => Some(secs),
+                };
+            }
+            (_, Some(count)) => level += count,
+            _ => rest.push(arg),
+        }
+    }
+
+    let console_level = match (quiet, level) {
+        (true, _) => Some(LevelFilter::Warn),
+        (false, 0) => None,
+        (false, 1) => Some(LevelFilter::Debug),
+        (false, _) => Some(LevelFilter::Trace),
+    };
+
+    let mut rest = rest.into_iter();
+    let command = match rest.next() {
+        None => Command::Daemon,
+        Some(name @ "setup") => {
+            let mut network = None;
+            let mut start = true;
+            let mut system = false;
+            while let Some(arg) = rest.next() {
+                match arg {
+                    "--network" => network = Some(value(&mut rest, arg)?.to_string()),
+                    "--no-start" | "--install-only" => start = false,
+                    "--system" => system = true,
+                    _ => return Err(unexpected(name, arg)),
+                }
+            }
+            if network.is_some() && (!start || system) {
+                return Err(
+                    "--no-start and --system can't be used with --network, which installs no service"
+                        .into(),
+                );
+            }
+            Command::Setup {
+                network,
+                start,
+                system,
+            }
+        }
+        Some(name @ "check") => {
+            let mut dry_run = false;
+            for arg in rest.by_ref() {
+                match arg {
+                    "--dry-run" => dry_run = true,
+                    _ => return Err(unexpected(name, arg)),
+                }
+            }
+            Command::Check { dry_run }
+        }
+        Some(name @ "status") => {
+            let mut json = false;
+            let mut watch = false;
+            let mut interval = None;
+            while let Some(arg) = rest.next() {
+                match arg {
+                    "--json" => json = true,
+                    "-w" | "--watch" => watch = true,
+                    "--interval" => {
+                        let secs = value(&mut rest, arg)?;
+                        interval = match secs.parse() {
+                            Ok(0) | Err(_) => return Err(format!("Invalid interval: {}", secs)),
+                            Ok(secs) => Some(secs),
+                        };
+                    }
+                    _ => return Err(unexpected(name, arg)),
+                }
+            }
+            if json && watch {
+                return Err("--watch can't be used with --json".into());
+            }
+            if interval.is_some() && !watch {
+                return Err("--interval only applies with --watch".into());
+            }
+            Command::Status {
+                json,
+                watch: watch.then(|| interval.unwrap_or(DEFAULT_WATCH_INTERVAL_SECS)),
+            }
+        }
+        Some(name @ "health") => {
+            let mut json = false;
+            for arg in rest.by_ref() {
+                match arg {
+                    "--json" => json = true,
+                    _ => return Err(unexpected(name, arg)),
+                }
+            }
+            Command::Health { json }
+        }
+        Some(name @ "logs") => {
+            let mut lines = DEFAULT_LOG_LINES;
+            let mut follow = false;
+            while let Some(arg) = rest.next() {
+                match arg {
+                    "-n" | "--lines" => {
+                        let count = value(&mut rest, arg)?;
+                        lines = count
+                            .parse()
+                            .map_err(|_| format!("Invalid line count: {}", count))?;
+                    }
+                    "-f" | "--follow" => follow = true,
+                    _ => return Err(unexpected(name, arg)),
+                }
+            }
+            Command::Logs { lines, follow }
+        }
+        Some(name @ "creds") => {
+            let action = match rest.next() {
+                Some("set") => CredsAction::Set,
+                Some("clear") => CredsAction::Clear,
+                Some("show-user") => CredsAction::ShowUser,
+                Some(other) => return Err(format!("Unknown creds action '{}'", other)),
+                None => return Err("creds requires an action: set, clear or show-user".into()),
+            };
+            let mut network = None;
+            while let Some(arg) = rest.next() {
+                match arg {
+                    "--network" => network = Some(value(&mut rest, arg)?.to_string()),
+                    _ => return Err(unexpected(name, arg)),
+                }
+            }
+            Command::Creds { action, network }
+        }
+        Some(name @ "diagnose") => {
+            let mut output = None;
+            let mut lines = DEFAULT_DIAGNOSE_LOG_LINES;
+            while let Some(arg) = rest.next() {
+                match arg {
+                    "-o" | "--output" => output = Some(value(&mut rest, arg)?.to_string()),
+                    "-n" | "--lines" => {
+                        let count = value(&mut rest, arg)?;
+                        lines = count
+                            .parse()
+                            .map_err(|_| format!("Invalid line count: {}", count))?;
+                    }
+                    _ => return Err(unexpected(name, arg)),
+                }
+            }
+            Command::Diagnose { output, lines }
+        }
+        Some(name @ "test-portal") => {
+            let mut file = None;
+            let mut url = None;
+            while let Some(arg) = rest.next() {
+                match arg {
+                    "--url" => url = Some(value(&mut rest, arg)?.to_string()),
+                    _ if file.is_none() && !arg.starts_with('-') => file = Some(arg.to_string()),
+                    _ => return Err(unexpected(name, arg)),
+                }
+            }
+            let file = file.ok_or("test-portal requires the path of a saved HTML file")?;
+            Command::TestPortal { file, url }
+        }
+        Some("logout") => Command::Logout,
+        Some("test-notification") => Command::TestNotification,
+        Some("restart") => Command::Restart,
+        Some("uninstall") => Command::Uninstall,
+        #[cfg(target_os = "windows")]
+        Some("service") => Command::Service,
+        Some("--print-config-schema") => Command::PrintConfigSchema,
+        Some("--version" | "-V" | "version") => Command::Version,
+        Some("--help" | "-h" | "help") => Command::Help,
+        Some(other) => return Err(format!("Unknown command '{}'", other)),
+    };
+
+    if let Some(arg) = rest.next() {
+        return Err(format!("Unexpected argument '{}'", arg));
+    }
+    if timeout.is_some()
+        && !matches!(
+            command,
+            Command::Status { .. }
+                | Command::Check { .. }
+                | Command::Health { .. }
+                | Command::Diagnose { .. }
+        )
+    {
+        return Err("--timeout only applies to status, check, health and diagnose".into());
+    }
+
+    Ok(Cli {
+        command,
+        console_level,
+        timeout,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(args: &[&str]) -> Command {
+        parse(args).unwrap().command
+    }
+
+    #[test]
+    fn no_arguments_runs_the_daemon() {
+        let cli = parse::<&str>(&[]).unwrap();
+        assert_eq!(cli.command, Command::Daemon);
+        assert_eq!(cli.console_level, None);
+        assert_eq!(cli.timeout, None);
+    }
+
+    #[test]
+    fn setup() {
+        assert_eq!(
+            command(&["setup"]),
+            Command::Setup {
+                network: None,
+                start: true,
+                system: false,
+            }
+        );
+        assert_eq!(
+            command(&["setup", "--install-only", "--system"]),
+            Command::Setup {
+                network: None,
+                start: false,
+                system: true,
+            }
+        );
+        assert_eq!(
+            command(&["setup", "--network", "Office"]),
+            Command::Setup {
+                network: Some("Office".into()),
+                start: true,
+                system: false,
+            }
+        );
+        assert!(parse(&["setup", "--network", "Office", "--system"]).is_err());
+        assert!(parse(&["setup", "--network", "Office", "--no-start"]).is_err());
+        assert!(parse(&["setup", "--network"]).is_err());
+    }
+
+    #[test]
+    fn check() {
+        assert_eq!(command(&["check"]), Command::Check { dry_run: false });
+        assert_eq!(
+            command(&["check", "--dry-run"]),
+            Command::Check { dry_run: true }
+        );
+        assert!(parse(&["check", "--json"]).is_err());
+    }
+
+    #[test]
+    fn status() {
+        assert_eq!(
+            command(&["status", "--json"]),
+            Command::Status {
+                json: true,
+                watch: None,
+            }
+        );
+        assert_eq!(
+            command(&["status", "--watch"]),
+            Command::Status {
+                json: false,
+                watch: Some(DEFAULT_WATCH_INTERVAL_SECS),
+            }
+        );
+        assert_eq!(
+            command(&["status", "-w", "--interval", "5"]),
+            Command::Status {
+                json: false,
+                watch: Some(5),
+            }
+        );
+        assert!(parse(&["status", "--watch", "--interval", "0"]).is_err());
+        assert!(parse(&["status", "--watch", "--interval", "soon"]).is_err());
+        assert!(parse(&["status", "--interval", "5"]).is_err());
+        assert!(parse(&["status", "--json", "--watch"]).is_err());
+    }
+
+    #[test]
+    fn health() {
+        assert_eq!(command(&["health"]), Command::Health { json: false });
+        assert_eq!(
+            command(&["health", "--json"]),
+            Command::Health { json: true }
+        );
+    }
+
+    #[test]
+    fn logs() {
+        assert_eq!(
+            command(&["logs"]),
+            Command::Logs {
+                lines: DEFAULT_LOG_LINES,
+                follow: false,
+            }
+        );
+        assert_eq!(
+            command(&["logs", "-n", "10", "--follow"]),
+            Command::Logs {
+                lines: 10,
+                follow: true,
+            }
+        );
+        assert!(parse(&["logs", "-n", "many"]).is_err());
+    }
+
+    #[test]
+    fn creds() {
+        assert_eq!(
+            command(&["creds", "set", "--network", "Office"]),
+            Command::Creds {
+                action: CredsAction::Set,
+                network: Some("Office".into()),
+            }
+        );
+        assert_eq!(
+            command(&["creds", "show-user"]),
+            Command::Creds {
+                action: CredsAction::ShowUser,
+                network: None,
+            }
+        );
+        assert!(parse(&["creds"]).is_err());
+        assert!(parse(&["creds", "rotate"]).is_err());
+    }
+
+    #[test]
+    fn diagnose() {
+        assert_eq!(
+            command(&["diagnose"]),
+            Command::Diagnose {
+                output: None,
+                lines: DEFAULT_DIAGNOSE_LOG_LINES,
+            }
+        );
+        assert_eq!(
+            command(&["diagnose", "-o", "bundle.tar.gz", "--lines", "20"]),
+            Command::Diagnose {
+                output: Some("bundle.tar.gz".into()),
+                lines: 20,
+            }
+        );
+    }
+
+    #[test]
+    fn test_portal() {
+        assert_eq!(
+            command(&["test-portal", "login.html", "--url", "http://10.0.0.1/"]),
+            Command::TestPortal {
+                file: "login.html".into(),
+                url: Some("http://10.0.0.1/".into()),
+            }
+        );
+        assert!(parse(&["test-portal"]).is_err());
+        assert!(parse(&["test-portal", "--url", "http://10.0.0.1/"]).is_err());
+    }
+
+    #[test]
+    fn simple_commands() {
+        assert_eq!(command(&["logout"]), Command::Logout);
+        assert_eq!(command(&["test-notification"]), Command::TestNotification);
+        assert_eq!(command(&["restart"]), Command::Restart);
+        assert_eq!(command(&["uninstall"]), Command::Uninstall);
+        assert_eq!(command(&["--version"]), Command::Version);
+        assert_eq!(command(&["help"]), Command::Help);
+        assert_eq!(
+            command(&["--print-config-schema"]),
+            Command::PrintConfigSchema
+        );
+        assert!(parse(&["frobnicate"]).is_err());
+    }
+
+    #[test]
+    fn verbosity_and_quiet() {
+        assert_eq!(
+            parse(&["-v", "check"]).unwrap().console_level,
+            Some(LevelFilter::Debug)
+        );
+        assert_eq!(
+            parse(&["-vv", "check"]).unwrap().console_level,
+            Some(LevelFilter::Trace)
+        );
+        assert_eq!(
+            parse(&["-v", "--verbose", "check"]).unwrap().console_level,
+            Some(LevelFilter::Trace)
+        );
+        // --quiet wins over any number of -v, in either order.
+        assert_eq!(
+            parse(&["-vv", "-q", "check"]).unwrap().console_level,
+            Some(LevelFilter::Warn)
+        );
+        assert_eq!(
+            parse(&["--quiet", "-v", "check"]).unwrap().console_level,
+            Some(LevelFilter::Warn)
+        );
+    }
+
+    #[test]
+    fn timeout() {
+        assert_eq!(
+            parse(&["--timeout", "5", "status"]).unwrap().timeout,
+            Some(5)
+        );
+        assert_eq!(
+            parse(&["check", "--timeout", "5"]).unwrap().timeout,
+            Some(5)
+        );
+        assert!(parse(&["--timeout", "0", "status"]).is_err());
+        assert!(parse(&["--timeout", "soon", "status"]).is_err());
+        assert!(parse(&["status", "--timeout"]).is_err());
+        assert!(parse(&["--timeout", "5", "logs"]).is_err());
+    }
+
+    #[test]
+    fn trailing_arguments() {
+        assert!(parse(&["logout", "now"]).is_err());
+        assert!(parse(&["restart", "--force"]).is_err());
+        assert!(parse(&["test-portal", "a.html", "b.html"]).is_err());
+    }
+}
@@ -0,0 +1,353 @@
+use crate::captive_portal::{
+    ConnectivityProbe, DetectionPatterns, FormOptions, HttpClients, HttpOptions, LoginFields,
+    LoginMethod, PreAuthStep, RetryPolicy, DEFAULT_USER_AGENT,
+};
+use crate::error::{AppError, Result};
+use crate::logging::LoggingConfig;
+use crate::network;
+use regex::Regex;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Seconds to wait between captive portal checks after a login, or while
+    /// online at first.
+    pub poll_interval_secs: u64,
+    /// Upper bound in seconds for the check interval while online without a
+    /// portal.
+    pub max_poll_interval_secs: u64,
+    /// How the check interval evolves while online without a portal.
+    pub idle_backoff: IdleBackoff,
+    /// Seconds before retrying after a failed or offline check; doubles on
+    /// every further failure.
+    pub failure_poll_interval_secs: u64,
+    /// Upper bound in seconds for the retry interval after failed or offline
+    /// checks.
+    pub max_failure_poll_interval_secs: u64,
+    /// Seconds between checks of the Wi-Fi SSID and local address; a change
+    /// triggers an immediate portal check. 0 disables.
+    pub ssid_poll_interval_secs: u64,
+    /// Restart the network watcher when it hasn't read the network for this
+    /// many seconds (at least three poll intervals).
+    pub ssid_watchdog_secs: u64,
+    /// Seconds the daemon waits after starting before its first check, so the
+    /// network can come up at boot; 0 checks right away.
+    pub startup_grace_secs: u64,
+    /// Seconds to wait for further network changes or check requests before
+    /// checking, so a burst triggers one check.
+    pub wakeup_debounce_secs: u64,
+    /// Timeout in seconds for each HTTP request to the probe or portal.
+    pub request_timeout_secs: u64,
+    /// Deadline in seconds for a whole portal check and login, including
+    /// retries; 0 disables.
+    pub check_timeout_secs: u64,
+    /// User-Agent header sent with every HTTP request.
+    pub user_agent: String,
+    /// Proxy for every HTTP request, e.g. `http://proxy.example.com:3128`;
+    /// unset uses the `HTTP_PROXY`/`HTTPS_PROXY` environment.
+    pub proxy_url: Option<String>,
+    /// Extra hosts or CIDR ranges that bypass `proxy_url`, such as the portal
+    /// gateway; private ranges always do.
+    pub proxy_bypass: Vec<String>,
+    /// Accept invalid or self-signed TLS certificates on portal pages (never
+    /// for the probe).
+    pub allow_invalid_portal_certs: bool,
+    /// Endpoint used to detect captive portals and verify internet access.
+    pub connectivity_probe: ConnectivityProbe,
+    /// More endpoints probed at the same time as `connectivity_probe` to detect
+    /// portals; the network counts as online as soon as any probe passes.
+    pub additional_connectivity_probes: Vec<ConnectivityProbe>,
+    /// Regex that finds the login URL in a probe response the built-in
+    /// detection misses; the URL is its named group `url`, and may be relative.
+    pub portal_url_pattern: Option<String>,
+    /// Regex that extracts the magic value from the portal URL as its named
+    /// group `magic`; the built-in rule takes the whole query string.
+    pub magic_value_pattern: Option<String>,
+    /// Retry policy for logging in to a detected portal.
+    pub retry: RetryPolicy,
+    /// How the login form is submitted; `get` puts the credentials in the query
+    /// string.
+    pub login_method: LoginMethod,
+    /// Names of the login form fields the credentials are submitted in.
+    pub login_fields: LoginFields,
+    /// Terms-of-service or "click to continue" page the portal shows before its
+    /// login form; unset when there is none.
+    pub pre_auth_step: Option<PreAuthStep>,
+    /// Pause logins after this many checks in a row whose login was rejected
+    /// for bad credentials, to avoid an account lockout; 0 never pauses.
+    pub credential_rejection_limit: u32,
+    /// Keyring service the credentials are stored under, instead of the service
+    /// name; lets isolated instances keep separate credentials.
+    pub keyring_service: Option<String>,
+    /// Wi-Fi SSIDs or gateway MAC addresses of the networks the daemon may log
+    /// in on; an empty list allows every network.
+    pub allowed_ssids: Vec<String>,
+    /// Portal logout URL; derived from the last portal login when unset.
+    pub logout_url: Option<String>,
+    /// Webhooks notified when the daemon logs in or fails.
+    pub hooks: HooksConfig,
+    /// Show desktop notifications; `ACP_NOTIFICATIONS=0` also disables them.
+    pub notifications_enabled: bool,
+    /// Where notifications are delivered.
+    pub notification_sink: NotificationSinkKind,
+    /// URL that receives a JSON POST for every notification when the sink uses
+    /// a webhook.
+    pub notification_webhook_url: Option<String>,
+    /// Seconds a desktop notification of a successful login stays on screen; 0
+    /// keeps it until dismissed.
+    pub notification_timeout_secs: u64,
+    /// Seconds a desktop notification of a failure stays on screen; 0 keeps it
+    /// until dismissed.
+    pub notification_failure_timeout_secs: u64,
+    /// Send one urgent notification once this many checks in a row failed; 0
+    /// disables.
+    pub failure_alert_threshold: u32,
+    /// Only alert when those failures all happened within this many seconds; 0
+    /// lifts the limit.
+    pub failure_alert_window_secs: u64,
+    /// Local address of an auto-refreshing status page for kiosk screens, e.g.
+    /// `127.0.0.1:8787`; unset disables. Needs the `status-page` build feature.
+    pub status_page_listen: Option<String>,
+    /// Seconds between refreshes of the status page, which also limits how
+    /// often it checks connectivity.
+    pub status_page_refresh_secs: u64,
+    /// Log file settings.
+    pub logging: LoggingConfig,
+    /// Exit the daemon after this many seconds so the service manager restarts
+    /// it fresh (Linux and macOS); unset or 0 never does.
+    pub max_uptime_secs: Option<u64>,
+    /// Detect portals and log what would be submitted, without ever sending
+    /// credentials.
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum IdleBackoff {
+    /// Double the interval on every check, up to `max_poll_interval_secs`.
+    #[default]
+    Grow,
+    /// Keep checking every `poll_interval_secs`.
+    Fixed,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationSinkKind {
+    /// Show a desktop notification.
+    #[default]
+    Desktop,
+    /// POST the notification to `notification_webhook_url`.
+    Webhook,
+    /// Use both the desktop and the webhook.
+    Both,
+}
+
+#[derive(Debug, Default, Deserialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct HooksConfig {
+    /// URL that receives a JSON POST after a successful login.
+    pub on_login: Option<String>,
+    /// URL that receives a JSON POST after a failed portal check or login.
+    pub on_failure: Option<String>,
+    /// URL that receives a JSON POST when `failure_alert_threshold` checks in a
+    /// row failed.
+    pub on_failure_alert: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            poll_interval_secs: 10,
+            max_poll_interval_secs: 300,
+            idle_backoff: IdleBackoff::default(),
+            failure_poll_interval_secs: 5,
+            max_failure_poll_interval_secs: 60,
+            ssid_poll_interval_secs: 5,
+            ssid_watchdog_secs: 60,
+            startup_grace_secs: 5,
+            wakeup_debounce_secs: 2,
+            request_timeout_secs: 10,
+            check_timeout_secs: 120,
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            proxy_url: None,
+            proxy_bypass: Vec::new(),
+            allow_invalid_portal_certs: false,
+            connectivity_probe: ConnectivityProbe::default(),
+            additional_connectivity_probes: Vec::new(),
+            portal_url_pattern: None,
+            magic_value_pattern: None,
+            retry: RetryPolicy::default(),
+            login_method: LoginMethod::default(),
+            login_fields: LoginFields::default(),
+            pre_auth_step: None,
+            credential_rejection_limit: 3,
+            keyring_service: None,
+            allowed_ssids: Vec::new(),
+            logout_url: None,
+            hooks: HooksConfig::default(),
+            notifications_enabled: true,
+            notification_sink: NotificationSinkKind::default(),
+            notification_webhook_url: None,
+            notification_timeout_secs: 5,
+            notification_failure_timeout_secs: 0,
+            failure_alert_threshold: 10,
+            failure_alert_window_secs: 3600,
+            status_page_listen: None,
+            status_page_refresh_secs: 10,
+            logging: LoggingConfig::default(),
+            max_uptime_secs: None,
+            dry_run: false,
+        }
+    }
+}
+
+impl Config {
+    /// `names` are the current network's gateway MAC and SSID, when known.
+    pub fn is_network_allowed(&self, names: &[&str]) -> bool {
+        self.allowed_ssids.is_empty()
+            || self
+                .allowed_ssids
+                .iter()
+                .any(|allowed| names.contains(&network::normalize_network_name(allowed).as_str()))
+    }
+
+    pub fn notifications_enabled(&self) -> bool {
+        match std::env::var("ACP_NOTIFICATIONS") {
+            Ok(value) => !matches!(value.trim(), "0" | "false" | "off" | "no"),
+            Err(_) => self.notifications_enabled,
+        }
+    }
+
+    pub fn http_clients(&self) -> Result<HttpClients> {
+        HttpClients::new(&self.http_options(), self.allow_invalid_portal_certs)
+    }
+
+    /// `connectivity_probe` followed by `additional_connectivity_probes`.
+    pub fn connectivity_probes(&self) -> Vec<ConnectivityProbe> {
+        std::iter::once(&self.connectivity_probe)
+            .chain(&self.additional_connectivity_probes)
+            .cloned()
+            .collect()
+    }
+
+    /// Compiles `portal_url_pattern` and `magic_value_pattern`.
+    pub fn detection_patterns(&self) -> Result<DetectionPatterns> {
+        Ok(DetectionPatterns {
+            portal_url: compile_pattern(
+                "portal_url_pattern",
+                self.portal_url_pattern.as_deref(),
+                "url",
+            )?,
+            magic_value: compile_pattern(
+                "magic_value_pattern",
+                self.magic_value_pattern.as_deref(),
+                "magic",
+            )?,
+        })
+    }
+
+    pub fn form_options(&self) -> FormOptions {
+        FormOptions {
+            method: self.login_method,
+            fields: self.login_fields.clone(),
+            pre_auth: self.pre_auth_step.clone(),
+        }
+    }
+
+    pub fn http_options(&self) -> HttpOptions {
+        HttpOptions {
+            proxy_url: self.proxy_url.clone(),
+            proxy_bypass: self.proxy_bypass.clone(),
+            timeout: Duration::from_secs(self.request_timeout_secs),
+            user_agent: self.user_agent.clone(),
+        }
+    }
+
+    /// Overrides `request_timeout_secs`, e.g. from the `--timeout` flag.
+    pub fn with_request_timeout(mut self, secs: Option<u64>) -> Self {
+        if let Some(secs) = secs {
+            self.request_timeout_secs = secs;
+        }
+        self
+    }
+
+    /// Rejects a zero for settings where it means no delay at all: a zero
+    /// interval would check in a busy loop, and a zero timeout fails every
+    /// request.
+    fn check_nonzero(&self) -> Result<()> {
+        let settings = [
+            ("poll_interval_secs", self.poll_interval_secs),
+            (
+                "failure_poll_interval_secs",
+                self.failure_poll_interval_secs,
+            ),
+            ("request_timeout_secs", self.request_timeout_secs),
+            ("status_page_refresh_secs", self.status_page_refresh_secs),
+        ];
+        match settings.iter().find(|(_, value)| *value == 0) {
+            Some((key, _)) => Err(AppError::Config(format!("{} must be at least 1", key))),
+            None => Ok(()),
+        }
+    }
+
+    pub fn load() -> Result<Self> {
+        let Some(path) = config_path() else {
+            return Ok(Self::default());
+        };
+
+        match fs::read_to_string(&path) {
+            Ok(contents) => {
+                let invalid = |e: &dyn std::fmt::Display| {
+                    AppError::Config(format!("{}: {}", path.display(), e))
+                };
+                let config: Self = toml::from_str(&contents).map_err(|e| invalid(&e))?;
+                if let Err(AppError::Config(e)) = config.detection_patterns() {
+                    return Err(invalid(&e));
+                }
+                if let Err(AppError::Config(e)) = config.check_nonzero() {
+                    return Err(invalid(&e));
+                }
+                Ok(config)
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// Compiles the user's `pattern` for `key`, which must have the named capture
+/// group `group`.
+fn compile_pattern(key: &str, pattern: Option<&str>, group: &str) -> Result<Option<Regex>> {
+    let Some(pattern) = pattern else {
+        return Ok(None);
+    };
+    let regex = Regex::new(pattern)
+        .map_err(|e| AppError::Config(format!("{} is not a valid regex: {}", key, e)))?;
+    if !regex.capture_names().any(|name| name == Some(group)) {
+        return Err(AppError::Config(format!(
+            "{} has no named capture group `{}`, e.g. (?P<{}>...)",
+            key, group, group
+        )));
+    }
+    Ok(Some(regex))
+}
+
+pub fn config_path() -> Option<PathBuf> {
+    if cfg!(target_os = "windows") {
+        dirs::config_dir().map(|dir| dir.join("acp").join("config.toml"))
+    } else {
+        dirs::home_dir().map(|dir| dir.join(".config/acp/config.toml"))
+    }
+}
+
+pub fn schema_json() -> Result<String> {
+    let schema = schemars::schema_for!(Config);
+    serde_json::to_string_pretty(&schema).map_err(|e| AppError::Config(e.to_string()))
+}
@@ -0,0 +1,207 @@
+use crate::config::Config;
+use crate::error::{AppError, Result};
+use crate::logging;
+use crate::network;
+use crate::state;
+use crate::SERVICE_NAME;
+use keyring::Entry;
+use secrecy::{ExposeSecret, SecretString};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+pub const USERNAME_ENV: &str = "ACP_USERNAME";
+pub const PASSWORD_ENV: &str = "ACP_PASSWORD";
+
+static KEYRING_SERVICE: OnceLock<String> = OnceLock::new();
+
+/// Where the credentials used for a login came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CredentialSource {
+    Environment,
+    Keyring,
+    File,
+}
+
+impl fmt::Display for CredentialSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CredentialSource::Environment => "environment",
+            CredentialSource::Keyring => "keyring",
+            CredentialSource::File => "credentials file",
+        })
+    }
+}
+
+/// Where `store_credentials` saved the credentials.
+pub enum CredentialStore {
+    Keyring,
+    File(PathBuf),
+}
+
+fn account(key: &str, network: Option<&str>) -> String {
+    match network {
+        Some(network) => format!("{}@{}", key, network::normalize_network_name(network)),
+        None => key.to_string(),
+    }
+}
+
+/// Uses `keyring_service` from `config` for every later keyring access.
+pub fn init(config: &Config) {
+    if let Some(service) = &config.keyring_service {
+        let _ = KEYRING_SERVICE.set(service.clone());
+    }
+}
+
+fn entry(account: &str) -> Result<Entry> {
+    let service = KEYRING_SERVICE.get().map_or(SERVICE_NAME, String::as_str);
+    Entry::new(service, account).map_err(AppError::from)
+}
+
+// No Secret Service on the session bus, or a locked/denied store.
+fn backend_unavailable(e: &keyring::Error) -> bool {
+    matches!(
+        e,
+        keyring::Error::PlatformFailure(_) | keyring::Error::NoStorageAccess(_)
+    )
+}
+
+pub fn fallback_file_path() -> Result<PathBuf> {
+    Ok(state::data_dir()?.join("credentials.json"))
+}
+
+fn load_fallback() -> Result<BTreeMap<String, String>> {
+    let path = fallback_file_path()?;
+    match fs::read_to_string(&path) {
+        Ok(contents) => serde_json::from_str(&contents).map_err(|e| AppError::CredentialsFile {
+            message: format!("failed to parse: {}", e),
+            path,
+        }),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn save_fallback(secrets: &BTreeMap<String, String>) -> Result<()> {
+    let path = fallback_file_path()?;
+    if secrets.is_empty() {
+        return match fs::remove_file(&path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        };
+    }
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let contents = serde_json::to_string_pretty(secrets)?;
+    // The file may hold the only copy of the credentials, so never truncate it in place.
+    state::write_private_atomically(&path, contents.as_bytes())?;
+    Ok(())
+}
+
+fn get_secret(account: &str) -> Result<Option<(String, CredentialSource)>> {
+    match entry(account)?.get_password() {
+        Ok(secret) => Ok(Some((secret, CredentialSource::Keyring))),
+        Err(keyring::Error::NoEntry) => Ok(None),
+        Err(e) if backend_unavailable(&e) => {
+            log::debug!("Keyring unavailable ({}), using the fallback file", e);
+            Ok(load_fallback()?
+                .remove(account)
+                .map(|secret| (secret, CredentialSource::File)))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn get_profile(network: Option<&str>) -> Result<Option<(String, SecretString, CredentialSource)>> {
+    let Some((username, source)) = get_secret(&account("ldap_username", network))? else {
+        return Ok(None);
+    };
+    Ok(get_secret(&account("ldap_password", network))?
+        .map(|(password, _)| (username, SecretString::from(password), source)))
+}
+
+fn find_credentials(networks: &[&str]) -> Result<(String, SecretString, CredentialSource)> {
+    if let (Ok(username), Ok(password)) = (std::env::var(USERNAME_ENV), std::env::var(PASSWORD_ENV))
+    {
+        return Ok((
+            username,
+            SecretString::from(password),
+            CredentialSource::Environment,
+        ));
+    }
+
+    for network in networks {
+        if let Some(credentials) = get_profile(Some(network))? {
+            return Ok(credentials);
+        }
+    }
+    get_profile(None)?.ok_or(AppError::CredentialsNotFound)
+}
+
+pub fn store_credentials(
+    username: &str,
+    password: &SecretString,
+    network: Option<&str>,
+) -> Result<CredentialStore> {
+    let username_account = account("ldap_username", network);
+    let password_account = account("ldap_password", network);
+
+    let password_entry = entry(&password_account)?;
+    let stored = entry(&username_account)?
+        .set_password(username)
+        .and_then(|()| password_entry.set_password(password.expose_secret()));
+    match stored {
+        Ok(()) => Ok(CredentialStore::Keyring),
+        Err(e) if backend_unavailable(&e) => {
+            let mut secrets = load_fallback()?;
+            secrets.insert(username_account, username.to_string());
+            secrets.insert(password_account, password.expose_secret().to_string());
+            save_fallback(&secrets)?;
+            Ok(CredentialStore::File(fallback_file_path()?))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Uses the profile of the first of `networks` that has one, e.g. the gateway
+/// MAC and then the SSID, falling back to the default credentials.
+pub fn get_credentials(networks: &[&str]) -> Result<(String, SecretString)> {
+    let (username, password, source) = find_credentials(networks)?;
+    logging::redact_secret(password.expose_secret());
+    log::debug!("Using credentials for {} from the {}", username, source);
+    Ok((username, password))
+}
+
+pub fn credential_source(networks: &[&str]) -> Result<CredentialSource> {
+    find_credentials(networks).map(|(_, _, source)| source)
+}
+
+/// Fails with the backend error when the system keyring can't be used, e.g.
+/// because no Secret Service provider is running.
+pub fn check_keyring() -> Result<()> {
+    match entry(&account("ldap_username", None))?.get_password() {
+        Ok(_) | Err(keyring::Error::NoEntry) => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub fn clear_credentials(network: Option<&str>) -> Result<()> {
+    let mut secrets = load_fallback()?;
+    for key in ["ldap_username", "ldap_password"] {
+        let account = account(key, network);
+        match entry(&account)?.delete_credential() {
+            Ok(()) | Err(keyring::Error::NoEntry) => {}
+            Err(e) if backend_unavailable(&e) => {}
+            Err(e) => return Err(e.into()),
+        }
+        secrets.remove(&account);
+    }
+    save_fallback(&secrets)
+}
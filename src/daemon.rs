@@ -0,0 +1,861 @@
+use crate::backoff::Backoff;
+use crate::captive_portal::{
+    self, FormOptions, HttpClients, LoginPage, PageLayout, Portal, PortalKind, PreAuthStep,
+    RetryPolicy,
+};
+use crate::config::{self, Config, IdleBackoff};
+use crate::credentials;
+use crate::error::{AppError, Result};
+use crate::events::{self, EventKind};
+use crate::hooks::{self, HookEvent};
+use crate::logging;
+use crate::network::{self, NetworkIdentity};
+use crate::notifications;
+use crate::state::{self, CachedPortal, ServiceState};
+use secrecy::SecretString;
+use std::fmt;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+const NETWORK_STABLE_POLLS: u32 = 2;
+const PID_FILE_NAME: &str = "acp.pid";
+
+/// Why the poll loop was woken before its interval elapsed.
+enum Wakeup {
+    NetworkChanged(NetworkChange),
+    #[cfg_attr(not(unix), allow(dead_code))]
+    Requested,
+}
+
+pub enum CheckOutcome {
+    NoPortal,
+    AlreadyLoggedIn,
+    Offline,
+    Skipped,
+    DryRun,
+    LoggedIn,
+}
+
+fn record_state(update: impl FnOnce(&mut ServiceState)) {
+    if let Err(e) = state::update_state_file(update) {
+        log::error!("Failed to update state file: {}", e);
+    }
+}
+
+pub(crate) const ISSUES_URL: &str = "https://github.com/AmanSikarwar/auto-captive-portal/issues";
+
+/// A form portal's login page, fetched before logging in. Its layout lets a
+/// failed login be compared with the page seen at the last success, and its
+/// URL is where the portal's redirects end, which the login then starts from.
+async fn prefetch_login_page(
+    portal: &Portal,
+    clients: &HttpClients,
+    pre_auth: Option<&PreAuthStep>,
+) -> Option<LoginPage> {
+    match portal.kind() {
+        PortalKind::BasicAuth | PortalKind::DigestAuth => None,
+        _ => captive_portal::fetch_login_page(&clients.portal, portal.url(), pre_auth)
+            .await
+            .ok(),
+    }
+}
+
+/// Keeps the magic value in `portal_url`, which identifies the login
+/// session, out of the logs.
+fn redact_magic(portal_url: &str) {
+    if let Some(magic) = captive_portal::magic_from_portal_url(portal_url) {
+        logging::redact_secret(&magic);
+    }
+}
+
+/// Logs in once through the login page cached for this network, skipping the
+/// portal's redirects. Returns `None` when the page is gone or the login fails
+/// for any reason but rejected credentials, so the caller falls back to the
+/// page the portal redirects to now.
+async fn login_with_cached_portal(
+    cached: &CachedPortal,
+    username: &str,
+    password: &SecretString,
+    config: &Config,
+    clients: &HttpClients,
+) -> Option<(Portal, PageLayout, Result<()>)> {
+    let fetched = captive_portal::fetch_login_page(
+        &clients.portal,
+        &cached.url,
+        config.pre_auth_step.as_ref(),
+    )
+    .await;
+    let page = match fetched {
+        Ok(page) => page,
+        Err(e) => {
+            log::info!(
+                "Cached login page {} is gone ({}), following the portal instead",
+                cached.url,
+                e
+            );
+            return None;
+        }
+    };
+    let portal = Portal::new(page.url, cached.kind);
+    redact_magic(portal.url());
+    log::info!("Logging in through the cached login page {}", portal.url());
+
+    let policy = RetryPolicy {
+        max_attempts: 1,
+        ..config.retry.clone()
+    };
+    match captive_portal::login_with_retry(
+        &portal,
+        username,
+        password,
+        &config.connectivity_probe,
+        clients,
+        &policy,
+        &config.form_options(),
+    )
+    .await
+    {
+        Err(e) if !e.is_credential_rejection() => {
+            log::warn!(
+                "Login through the cached login page failed ({}), following the portal instead",
+                e
+            );
+            None
+        }
+        result => Some((portal, page.layout, result)),
+    }
+}
+
+/// Warns loudly when a login fails on a login page that changed since the
+/// last successful login and no longer has a recognizable password field.
+fn report_layout_change(portal: &Portal, layout: &PageLayout) {
+    let previous = state::load_state()
+        .ok()
+        .and_then(|state| state.last_portal_html_hash);
+    if layout.has_password_field || previous.is_none_or(|hash| hash == layout.hash) {
+        return;
+    }
+
+    let message = format!(
+        "The login page at {} changed since the last successful login and its login form \
+         is no longer recognized; the portal was probably redesigned. Please report it at {}",
+        captive_portal::shareable_portal_url(portal.url()),
+        ISSUES_URL
+    );
+    log::error!("{}", message);
+    events::record(EventKind::PortalChanged, Some(portal.url()));
+    notifications::send_notification(HookEvent::Failure, &message, Some(portal.url()));
+}
+
+fn report_credential_rejection(config: &Config, portal: &Portal) {
+    let limit = config.credential_rejection_limit;
+    let mut paused = false;
+    record_state(|state| paused = state.record_credential_rejection(limit));
+    if !paused {
+        return;
+    }
+
+    let message = format!(
+        "The portal rejected the credentials {} times in a row, so logins are paused to \
+         avoid locking the account. Run `acp-script creds set` to update them",
+        limit
+    );
+    log::error!("{}", message);
+    events::record(EventKind::LoginsPaused, Some(portal.url()));
+    notifications::send_notification(HookEvent::Failure, &message, Some(portal.url()));
+}
+
+/// Counts a failed check, and escalates once when `failure_alert_threshold`
+/// checks in a row failed, instead of staying as quiet as a single failure.
+async fn record_failure(config: &Config, ssid: Option<&str>, portal_url: Option<&str>) {
+    let threshold = config.failure_alert_threshold;
+    let window = Duration::from_secs(config.failure_alert_window_secs);
+    let mut alert = false;
+    record_state(|state| alert = state.record_failure(threshold, window));
+    if !alert {
+        return;
+    }
+
+    let message = format!(
+        "Auto Captive Portal has failed {} times in a row; check your credentials or network",
+        threshold
+    );
+    log::error!("{}", message);
+    events::record(EventKind::FailureAlert, portal_url);
+    notifications::send_notification(HookEvent::FailureAlert, &message, portal_url);
+    hooks::send_hook(
+        config.hooks.on_failure_alert.as_deref(),
+        &config.http_options(),
+        HookEvent::FailureAlert,
+        ssid,
+        portal_url,
+    )
+    .await;
+}
+
+/// Lifts a pause after rejected credentials, for explicitly requested checks.
+pub fn resume_logins() {
+    if state::load_state().is_ok_and(|state| state.logins_paused) {
+        log::info!("Resuming logins paused after rejected credentials");
+        record_state(ServiceState::resume_logins);
+    }
+}
+
+async fn report_dry_run(
+    portal: &Portal,
+    clients: &HttpClients,
+    options: &FormOptions,
+) -> Result<()> {
+    log::info!("Dry run: credentials will not be read or submitted");
+    if let Some(magic) = captive_portal::magic_from_portal_url(portal.url()) {
+        log::info!("Magic value: {}", magic);
+    }
+
+    log::info!("Portal kind: {}", portal.kind());
+    match portal.kind() {
+        PortalKind::BasicAuth | PortalKind::DigestAuth => {
+            log::info!(
+                "Portal uses {}; credentials would be sent to {}",
+                portal.kind(),
+                portal.url()
+            );
+        }
+        _ => {
+            let page = captive_portal::fetch_login_page(
+                &clients.portal,
+                portal.url(),
+                options.pre_auth.as_ref(),
+            )
+            .await?;
+            if page.url.as_str() != portal.url() {
+                log::info!("Login page is at {}", page.url);
+                if let Some(magic) = captive_portal::magic_from_portal_url(page.url.as_str()) {
+                    log::info!("Magic value at the login page: {}", magic);
+                }
+            }
+            log::info!(
+                "Login form would be submitted with {} to {}",
+                options.method.resolve(&page.form.method),
+                page.form.action
+            );
+            log::info!(
+                "Credentials would be sent in the fields {} and {}",
+                options.fields.username,
+                options.fields.password
+            );
+            let mut fields: Vec<_> = page.hidden_fields.iter().collect();
+            fields.sort();
+            for (name, value) in fields {
+                log::info!("Hidden field {} = {:?}", name, value);
+            }
+        }
+    }
+    Ok(())
+}
+
+pub async fn check_and_login(config: &Config, clients: &HttpClients) -> Result<CheckOutcome> {
+    let http = config.http_options();
+    record_state(ServiceState::record_check);
+    let network = NetworkIdentity::current();
+    let ssid = network.ssid.clone();
+    let network_key = network.names().first().map(|name| name.to_string());
+    events::record(EventKind::CheckStarted, ssid.as_deref());
+
+    let mut portal = match captive_portal::detect_with_probes(
+        &config.connectivity_probes(),
+        &http,
+        &clients.probe,
+    )
+    .await
+    {
+        Ok(Some(portal)) => {
+            record_state(|state| state.record_probe(Some(&portal)));
+            portal
+        }
+        Ok(None) => {
+            record_state(|state| {
+                state.record_probe(None);
+                state.end_failure_streak();
+            });
+            if state::load_state().is_ok_and(|state| state.logged_in_on(ssid.as_deref())) {
+                log::info!("Already logged in to the captive portal");
+                return Ok(CheckOutcome::AlreadyLoggedIn);
+            }
+            log::info!("No captive portal detected");
+            return Ok(CheckOutcome::NoPortal);
+        }
+        Err(e @ AppError::DnsResolution { .. }) => {
+            log::warn!("{}; treating the network as offline", e);
+            events::record(EventKind::Offline, Some(&e.to_string()));
+            return Ok(CheckOutcome::Offline);
+        }
+        Err(e) => {
+            log::error!("Portal check failed: {}", e);
+            record_failure(config, ssid.as_deref(), None).await;
+            events::record(EventKind::CheckFailed, Some(&e.to_string()));
+            hooks::send_hook(
+                config.hooks.on_failure.as_deref(),
+                &http,
+                HookEvent::Failure,
+                ssid.as_deref(),
+                None,
+            )
+            .await;
+            return Err(e);
+        }
+    };
+
+    // A dry run exists to show the magic value, so it stays visible there.
+    if !config.dry_run {
+        redact_magic(portal.url());
+    }
+
+    if !config.is_network_allowed(&network.names()) {
+        log::warn!(
+            "Captive portal at {} on untrusted network {}, skipping login",
+            portal.url(),
+            match network.names().join(", ") {
+                names if names.is_empty() => "<unknown>".to_string(),
+                names => names,
+            }
+        );
+        return Ok(CheckOutcome::Skipped);
+    }
+
+    log::info!(
+        "Captive portal detected at {} ({})",
+        portal.url(),
+        portal.kind()
+    );
+    events::record(EventKind::PortalDetected, Some(portal.url()));
+    if config.dry_run {
+        report_dry_run(&portal, clients, &config.form_options()).await?;
+        return Ok(CheckOutcome::DryRun);
+    }
+
+    if state::load_state().is_ok_and(|state| state.logins_paused) {
+        log::warn!(
+            "Not logging in to {}: logins are paused because the portal kept rejecting the \
+             credentials. Run `acp-script creds set` or `acp-script check` to resume",
+            portal.url()
+        );
+        return Ok(CheckOutcome::Skipped);
+    }
+
+    // Only a portal that still redirects to the same host as when its login
+    // page was cached gets the shortcut.
+    let detected = portal.clone();
+    let cached = network_key.as_deref().and_then(|key| {
+        let mut state = state::load_state().ok()?;
+        state
+            .portal_cache
+            .remove(key)
+            .filter(|cached| cached.matches(&portal))
+    });
+
+    let mut layout = None;
+    let result = match credentials::get_credentials(&network.names()) {
+        Ok((username, password)) => {
+            let cached_login = match &cached {
+                Some(cached) => {
+                    login_with_cached_portal(cached, &username, &password, config, clients).await
+                }
+                None => None,
+            };
+            if let Some((login_page, page_layout, result)) = cached_login {
+                portal = login_page;
+                layout = Some(page_layout);
+                result
+            } else {
+                if let (Some(key), Some(_)) = (&network_key, &cached) {
+                    record_state(|state| {
+                        state.portal_cache.remove(key);
+                    });
+                }
+                if let Some(page) =
+                    prefetch_login_page(&portal, clients, config.pre_auth_step.as_ref()).await
+                {
+                    if page.url.as_str() != portal.url() {
+                        redact_magic(page.url.as_str());
+                        log::info!("Portal login page is at {}", page.url);
+                        portal = Portal::new(page.url, portal.kind());
+                    }
+                    layout = Some(page.layout);
+                }
+                captive_portal::login_with_retry(
+                    &portal,
+                    &username,
+                    &password,
+                    &config.connectivity_probe,
+                    clients,
+                    &config.retry,
+                    &config.form_options(),
+                )
+                .await
+            }
+        }
+        Err(e) => Err(e),
+    };
+
+    if let Err(e) = result {
+        log::error!("Login failed: {}", e);
+        record_failure(config, ssid.as_deref(), Some(portal.url())).await;
+        events::record(EventKind::LoginFailed, Some(&e.to_string()));
+        if e.is_credential_rejection() {
+            report_credential_rejection(config, &portal);
+        }
+        if let Some(layout) = &layout {
+            report_layout_change(&portal, layout);
+        }
+        hooks::send_hook(
+            config.hooks.on_failure.as_deref(),
+            &http,
+            HookEvent::Failure,
+            ssid.as_deref(),
+            Some(portal.url()),
+        )
+        .await;
+        return Err(e);
+    }
+
+    record_state(|state| {
+        state.record_login(&portal, ssid.as_deref());
+        if let Some(layout) = layout {
+            state.last_portal_html_hash = Some(layout.hash);
+            if let Some(key) = &network_key {
+                state.cache_portal(key, &detected, &portal);
+            }
+        }
+    });
+    events::record(EventKind::LoginSucceeded, Some(portal.url()));
+    notifications::send_notification(
+        HookEvent::Login,
+        "Captive portal detected and logged in successfully",
+        Some(portal.url()),
+    );
+    hooks::send_hook(
+        config.hooks.on_login.as_deref(),
+        &http,
+        HookEvent::Login,
+        ssid.as_deref(),
+        Some(portal.url()),
+    )
+    .await;
+
+    Ok(CheckOutcome::LoggedIn)
+}
+
+/// Runs `check_and_login` within `check_timeout_secs`. A check that runs out of
+/// time is recorded as a failure, since a login form may have been submitted.
+pub async fn check_with_deadline(config: &Config, clients: &HttpClients) -> Result<CheckOutcome> {
+    if config.check_timeout_secs == 0 {
+        return check_and_login(config, clients).await;
+    }
+
+    let deadline = Duration::from_secs(config.check_timeout_secs);
+    match tokio::time::timeout(deadline, check_and_login(config, clients)).await {
+        Ok(result) => result,
+        Err(_) => {
+            let e = AppError::CheckTimedOut {
+                secs: config.check_timeout_secs,
+            };
+            log::error!("{}", e);
+            record_failure(config, None, None).await;
+            events::record(EventKind::CheckFailed, Some(&e.to_string()));
+            Err(e)
+        }
+    }
+}
+
+/// The parts of the network the watcher compares between polls.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct NetworkChange {
+    ssid: Option<String>,
+    address: Option<IpAddr>,
+}
+
+impl fmt::Display for NetworkChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.ssid, self.address) {
+            (Some(ssid), Some(address)) => write!(f, "Wi-Fi {}, address {}", ssid, address),
+            (Some(ssid), None) => write!(f, "Wi-Fi {}, no address yet", ssid),
+            (None, Some(address)) => write!(f, "address {}", address),
+            (None, None) => f.write_str("no network"),
+        }
+    }
+}
+
+async fn read_network() -> NetworkChange {
+    tokio::task::spawn_blocking(|| NetworkChange {
+        ssid: network::current_ssid(),
+        address: network::local_address(),
+    })
+    .await
+    .unwrap_or_default()
+}
+
+type Heartbeat = Arc<Mutex<Instant>>;
+
+fn beat(heartbeat: &Heartbeat) {
+    *heartbeat.lock().unwrap_or_else(PoisonError::into_inner) = Instant::now();
+}
+
+async fn watch_network(interval: Duration, tx: mpsc::Sender<Wakeup>, heartbeat: Heartbeat) {
+    let mut current = read_network().await;
+    beat(&heartbeat);
+    let mut candidate = NetworkChange::default();
+    let mut seen = 0;
+
+    loop {
+        tokio::time::sleep(interval).await;
+        let network = read_network().await;
+        beat(&heartbeat);
+        if network == current {
+            seen = 0;
+            continue;
+        }
+
+        // Wait until the new network is stable so a brief reconnect doesn't trigger twice.
+        if seen > 0 && network == candidate {
+            seen += 1;
+        } else {
+            candidate = network.clone();
+            seen = 1;
+        }
+        if seen < NETWORK_STABLE_POLLS {
+            continue;
+        }
+
+        current = network;
+        seen = 0;
+        // Losing the network leaves nothing to log in to.
+        let lost = current.ssid.is_none() && current.address.is_none();
+        if !lost
+            && tx
+                .send(Wakeup::NetworkChanged(current.clone()))
+                .await
+                .is_err()
+        {
+            return;
+        }
+    }
+}
+
+// Restarts the watcher when it exits or stops reading the network, e.g. because
+// the platform's Wi-Fi tool hangs. Every watcher sends on a clone of `tx`.
+async fn supervise_network_watcher(interval: Duration, window: Duration, tx: mpsc::Sender<Wakeup>) {
+    loop {
+        let heartbeat = Arc::new(Mutex::new(Instant::now()));
+        let watcher = tokio::spawn(watch_network(interval, tx.clone(), heartbeat.clone()));
+
+        loop {
+            tokio::time::sleep(window).await;
+            if tx.is_closed() {
+                watcher.abort();
+                return;
+            }
+            if watcher.is_finished() {
+                log::warn!("Network watcher stopped, restarting it");
+                break;
+            }
+            let silent = heartbeat
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .elapsed();
+            if silent > window {
+                log::warn!(
+                    "Network watcher hasn't read the network for {}s, restarting it",
+                    silent.as_secs()
+                );
+                watcher.abort();
+                break;
+            }
+        }
+    }
+}
+
+// `kill -USR1` asks a running daemon for an immediate check, e.g. after
+// plugging in ethernet.
+#[cfg(unix)]
+async fn watch_check_requests(tx: mpsc::Sender<Wakeup>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut sigusr1 = match signal(SignalKind::user_defined1()) {
+        Ok(sigusr1) => sigusr1,
+        Err(e) => {
+            log::warn!("Failed to listen for SIGUSR1: {}", e);
+            return;
+        }
+    };
+    while sigusr1.recv().await.is_some() {
+        // A full channel already has a wakeup pending.
+        if let Err(mpsc::error::TrySendError::Closed(_)) = tx.try_send(Wakeup::Requested) {
+            return;
+        }
+    }
+}
+
+fn announce(wakeup: &Wakeup) {
+    match wakeup {
+        Wakeup::NetworkChanged(change) => {
+            log::info!("Network changed: {}", change);
+            events::record(EventKind::NetworkChanged, Some(&change.to_string()));
+        }
+        Wakeup::Requested => {
+            log::info!("Check requested via SIGUSR1");
+            resume_logins();
+        }
+    }
+}
+
+async fn poll_loop(config: &Config, clients: &HttpClients) -> Result<()> {
+    // Checks while online follow `idle`; after a failed or offline check the
+    // next one follows `failures`, so recovery doesn't wait for the idle timer.
+    let mut idle = Backoff::new(
+        Duration::from_secs(config.poll_interval_secs),
+        Duration::from_secs(config.max_poll_interval_secs),
+    );
+    let mut failures = Backoff::new(
+        Duration::from_secs(config.failure_poll_interval_secs),
+        Duration::from_secs(config.max_failure_poll_interval_secs),
+    );
+
+    let (tx, mut wakeups) = mpsc::channel(1);
+    #[cfg(unix)]
+    tokio::spawn(watch_check_requests(tx.clone()));
+    if config.ssid_poll_interval_secs > 0 {
+        let interval = Duration::from_secs(config.ssid_poll_interval_secs);
+        let window = Duration::from_secs(config.ssid_watchdog_secs).max(interval * 3);
+        tokio::spawn(supervise_network_watcher(interval, window, tx));
+    }
+
+    if config.startup_grace_secs > 0 {
+        log::info!(
+            "Waiting {}s for the network to come up before the first check",
+            config.startup_grace_secs
+        );
+        tokio::time::sleep(Duration::from_secs(config.startup_grace_secs)).await;
+    }
+    let mut first_check = true;
+
+    loop {
+        if std::mem::take(&mut first_check) && !network::has_usable_address() {
+            // Checking now would only fail and push the next check back.
+            log::info!("No network address yet, deferring the initial check");
+            let delay = Duration::from_secs(config.poll_interval_secs);
+            record_state(|state| state.schedule_check(delay, false));
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                Some(wakeup) = wakeups.recv() => announce(&wakeup),
+            }
+            continue;
+        }
+
+        let failed = match check_with_deadline(config, clients).await {
+            Ok(CheckOutcome::NoPortal | CheckOutcome::AlreadyLoggedIn)
+                if config.idle_backoff == IdleBackoff::Fixed =>
+            {
+                idle.reset();
+                false
+            }
+            Ok(
+                CheckOutcome::NoPortal
+                | CheckOutcome::AlreadyLoggedIn
+                | CheckOutcome::Skipped
+                | CheckOutcome::DryRun,
+            ) => false,
+            Ok(CheckOutcome::LoggedIn) => {
+                idle.reset();
+                false
+            }
+            // A portal that intercepts DNS usually only needs a login.
+            // Errors are already logged; the failure backoff paces the retries.
+            Ok(CheckOutcome::Offline) | Err(_) => true,
+        };
+        let delay = if failed {
+            idle.reset();
+            failures.next()
+        } else {
+            failures.reset();
+            idle.next()
+        };
+        record_state(|state| state.schedule_check(delay, failed));
+
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            Some(wakeup) = wakeups.recv() => {
+                announce(&wakeup);
+                // Coalesce a burst of wakeups, e.g. a network flapping while
+                // it connects, into a single check.
+                let debounce = Duration::from_secs(config.wakeup_debounce_secs);
+                while let Ok(Some(wakeup)) = tokio::time::timeout(debounce, wakeups.recv()).await {
+                    announce(&wakeup);
+                }
+                idle.reset();
+                failures.reset();
+                record_state(|state| state.schedule_check(Duration::ZERO, false));
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn shutdown_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    match signal(SignalKind::terminate()) {
+        Ok(mut sigterm) => {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = sigterm.recv() => {}
+            }
+        }
+        Err(e) => {
+            log::warn!("Failed to listen for SIGTERM: {}", e);
+            let _ = tokio::signal::ctrl_c().await;
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn shutdown_signal() {
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Held for the daemon's lifetime so a second instance refuses to start. The
+/// OS releases the lock when the process dies, so a PID file left behind by a
+/// crash never blocks the next start.
+struct InstanceLock {
+    path: PathBuf,
+    _file: File,
+}
+
+impl InstanceLock {
+    fn acquire() -> Result<Self> {
+        let path = state::data_dir()?.join(PID_FILE_NAME);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+
+        let mut previous = String::new();
+        match file.try_lock() {
+            Ok(()) => {
+                let _ = file.read_to_string(&mut previous);
+            }
+            Err(TryLockError::WouldBlock) => {
+                let _ = file.read_to_string(&mut previous);
+                return Err(AppError::AlreadyRunning {
+                    pid: previous.trim().parse().ok(),
+                });
+            }
+            Err(TryLockError::Error(e)) => return Err(e.into()),
+        }
+        if !previous.trim().is_empty() {
+            log::debug!("Replacing stale PID file of process {}", previous.trim());
+        }
+
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        write!(file, "{}", std::process::id())?;
+        Ok(Self { path, _file: file })
+    }
+}
+
+impl Drop for InstanceLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+pub async fn run(console_level: Option<log::LevelFilter>) -> Result<()> {
+    run_until(console_level, shutdown_signal()).await
+}
+
+/// Runs the daemon until `shutdown` completes. A check or login in progress
+/// is dropped; state updates are atomic, so it leaves no partial writes.
+pub async fn run_until(
+    console_level: Option<log::LevelFilter>,
+    shutdown: impl std::future::Future<Output = ()>,
+) -> Result<()> {
+    let config = Config::load()?;
+    logging::init_logging(&config.logging, console_level)?;
+    let _instance = InstanceLock::acquire()?;
+    notifications::init(&config);
+    credentials::init(&config);
+    captive_portal::init(config.detection_patterns()?);
+    let clients = config.http_clients()?;
+    match config::config_path() {
+        Some(path) if path.exists() => log::info!("Loaded config from {}", path.display()),
+        _ => log::info!("No config file found, using defaults"),
+    }
+    log::info!(
+        "Polling every {}s (up to {}s while idle, {:?} backoff; {}s up to {}s after failures), \
+         request timeout {}s, probe {}",
+        config.poll_interval_secs,
+        config.max_poll_interval_secs,
+        config.idle_backoff,
+        config.failure_poll_interval_secs,
+        config.max_failure_poll_interval_secs,
+        config.request_timeout_secs,
+        config.connectivity_probe.url
+    );
+
+    if let Some(listen) = &config.status_page_listen {
+        #[cfg(feature = "status-page")]
+        tokio::spawn(crate::status_page::serve(
+            listen.clone(),
+            Duration::from_secs(config.status_page_refresh_secs),
+        ));
+        #[cfg(not(feature = "status-page"))]
+        log::warn!(
+            "status_page_listen is set to {}, but this build has no status page (built without \
+             the status-page feature)",
+            listen
+        );
+    }
+
+    // The Windows service manager won't restart a service that stopped itself.
+    let max_uptime = config
+        .max_uptime_secs
+        .filter(|&secs| secs > 0 && !cfg!(target_os = "windows"));
+    if cfg!(target_os = "windows") && config.max_uptime_secs.is_some() {
+        log::warn!("max_uptime_secs is only supported on Linux and macOS, ignoring it");
+    }
+    let uptime_expired = async {
+        match max_uptime {
+            Some(secs) => tokio::time::sleep(Duration::from_secs(secs)).await,
+            None => std::future::pending().await,
+        }
+    };
+
+    tokio::select! {
+        result = poll_loop(&config, &clients) => result,
+        _ = shutdown => {
+            log::info!("Shutdown requested, stopping");
+            record_state(|state| state.next_check = None);
+            events::record(EventKind::Stopped, None);
+            log::logger().flush();
+            Ok(())
+        }
+        _ = uptime_expired => {
+            log::info!(
+                "Scheduled restart: running for max_uptime_secs ({}s), exiting so the service manager starts a fresh daemon",
+                max_uptime.unwrap_or_default()
+            );
+            events::record(EventKind::ScheduledRestart, None);
+            log::logger().flush();
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,406 @@
+//! `acp-script diagnose`: bundles what maintainers need to debug a portal
+//! into one archive for a bug report, with credentials and session tokens
+//! replaced by `[redacted]`. Nothing is sent anywhere.
+
+use crate::captive_portal::{self, PortalKind, SendLogged};
+use crate::config::Config;
+use crate::credentials;
+use crate::daemon;
+use crate::error::{AppError, Result};
+use crate::health;
+use crate::logging;
+use crate::network::NetworkIdentity;
+use crate::service;
+use regex::Regex;
+use reqwest::Url;
+use secrecy::ExposeSecret;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::LazyLock;
+
+// Usernames, network names and hidden field values follow the length limit
+// of `logging::redact_secret`; the password and magic value are masked
+// whatever their length.
+const MIN_TOKEN_LEN: usize = 4;
+
+// The query of an absolute URL, or of a path as in a `Location` header.
+static URL_QUERY_RE: LazyLock<Option<Regex>> = LazyLock::new(|| {
+    Regex::new(r#"((?:https?://|/)[^\s"'<>?#]*)\?[^\s"'<>#)]*"#)
+        .map_err(|e| log::error!("Invalid URL query pattern: {}", e))
+        .ok()
+});
+
+/// Masks the credentials, magic values and form tokens found along the way,
+/// on top of what the log redaction already knows.
+#[derive(Default)]
+struct Redactor {
+    secrets: Vec<String>,
+}
+
+impl Redactor {
+    fn add(&mut self, secret: &str) {
+        if !secret.is_empty() && !self.secrets.iter().any(|s| s == secret) {
+            self.secrets.push(secret.to_string());
+            self.secrets.sort_by_key(|s| std::cmp::Reverse(s.len()));
+        }
+    }
+
+    fn add_token(&mut self, token: &str) {
+        if token.chars().count() >= MIN_TOKEN_LEN {
+            self.add(token);
+        }
+    }
+
+    /// `text` with every known secret masked and the query of every URL
+    /// dropped, since queries carry session tokens of past logins too.
+    fn apply(&self, text: &str) -> String {
+        let mut text = logging::redact(text).into_owned();
+        for secret in &self.secrets {
+            text = text.replace(secret.as_str(), "[redacted]");
+        }
+        match URL_QUERY_RE.as_ref() {
+            Some(query_re) => query_re.replace_all(&text, "$1?[redacted]").into_owned(),
+            None => text,
+        }
+    }
+}
+
+/// A page fetched for the bundle, as received.
+struct Page {
+    url: Url,
+    status: String,
+    location: Option<String>,
+    html: String,
+}
+
+async fn fetch_page(client: &reqwest::Client, url: &str) -> Result<Page> {
+    let resp = client.get(url).send_logged().await?;
+    Ok(Page {
+        url: resp.url().clone(),
+        status: resp.status().to_string(),
+        location: resp
+            .headers()
+            .get(reqwest::header::LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .map(str::to_string),
+        html: resp.text().await?,
+    })
+}
+
+/// What every extractor finds in `page`, for `extractors.txt`.
+fn describe_page(
+    out: &mut String,
+    name: &str,
+    page: &Page,
+    config: &Config,
+    redactor: &mut Redactor,
+) {
+    let _ = writeln!(out, "== {} ==", name);
+    let _ = writeln!(out, "URL: {}", page.url);
+    let _ = writeln!(out, "Status: {}", page.status);
+    if let Some(location) = &page.location {
+        let _ = writeln!(out, "Location: {}", location);
+    }
+    let found = |value: Option<String>| value.unwrap_or_else(|| "none".to_string());
+    let _ = writeln!(
+        out,
+        "portal_url_pattern: {}",
+        found(captive_portal::extract_configured_portal_url(&page.html))
+    );
+    let _ = writeln!(
+        out,
+        "JavaScript redirect: {}",
+        found(captive_portal::extract_javascript_redirect_url(&page.html))
+    );
+    let _ = writeln!(
+        out,
+        "Meta refresh: {}",
+        found(captive_portal::extract_meta_refresh_url(&page.html))
+    );
+    let magic = captive_portal::magic_from_portal_url(page.url.as_str());
+    if let Some(magic) = &magic {
+        redactor.add(magic);
+    }
+    let _ = writeln!(
+        out,
+        "Magic value in the URL: {}",
+        match magic {
+            Some(magic) => format!("present, {} characters", magic.len()),
+            None => "none".to_string(),
+        }
+    );
+
+    let login_page = captive_portal::parse_login_page(&page.html, &page.url);
+    let _ = writeln!(
+        out,
+        "Password field: {}",
+        if login_page.layout.has_password_field {
+            "yes"
+        } else {
+            "no"
+        }
+    );
+    let _ = match captive_portal::extract_login_form(&page.html, &page.url) {
+        Some(form) => writeln!(out, "Login form: {} to {}", form.method, form.action),
+        None => writeln!(out, "Login form: none"),
+    };
+    let mut fields: Vec<_> = login_page.hidden_fields.iter().collect();
+    fields.sort();
+    for (field, value) in fields {
+        // Portals echo the magic value back in a hidden field of that name.
+        if field == "magic" {
+            redactor.add(value);
+        } else {
+            redactor.add_token(value);
+        }
+        let _ = writeln!(out, "  hidden field {} ({} characters)", field, value.len());
+    }
+    let _ = writeln!(out, "Layout hash: {}", login_page.layout.hash);
+    if let (false, Some(step)) = (login_page.layout.has_password_field, &config.pre_auth_step) {
+        let _ = writeln!(
+            out,
+            "Pre-auth step: {}",
+            match captive_portal::find_pre_auth_target(&page.html, &page.url, step) {
+                Some(target) => target.to_string(),
+                None => "no link or form matches pre_auth_step".to_string(),
+            }
+        );
+    }
+    let _ = writeln!(out);
+}
+
+fn system_info() -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "Build: {}", crate::BUILD);
+    let _ = writeln!(
+        out,
+        "OS: {} {}",
+        std::env::consts::OS,
+        std::env::consts::ARCH
+    );
+    let release = fs::read_to_string("/etc/os-release")
+        .ok()
+        .and_then(|release| {
+            release
+                .lines()
+                .find_map(|line| line.strip_prefix("PRETTY_NAME="))
+                .map(|name| name.trim_matches('"').to_string())
+        });
+    if let Some(release) = release {
+        let _ = writeln!(out, "Distribution: {}", release);
+    }
+    let _ = writeln!(
+        out,
+        "Service running: {}",
+        if service::is_service_running() {
+            "yes"
+        } else {
+            "no"
+        }
+    );
+    out
+}
+
+fn last_log_lines(lines: usize) -> String {
+    let Ok(path) = logging::get_log_file_path() else {
+        return "No log file location\n".to_string();
+    };
+    match fs::read(&path) {
+        Ok(contents) => {
+            let text = String::from_utf8_lossy(&contents);
+            let all: Vec<&str> = text.lines().collect();
+            let mut out = all[all.len().saturating_sub(lines)..].join("\n");
+            out.push('\n');
+            out
+        }
+        Err(e) => format!("No log file at {}: {}\n", path.display(), e),
+    }
+}
+
+/// Packs `dir` into the gzipped tarball `output` with the system's `tar`,
+/// which Linux, macOS and Windows 10 and later all have.
+fn archive(dir: &Path, output: &Path) -> Result<()> {
+    let failed = |reason: String| {
+        AppError::Io(std::io::Error::other(format!(
+            "could not create {}: {}",
+            output.display(),
+            reason
+        )))
+    };
+    let status = Command::new("tar")
+        .arg("-czf")
+        .arg(output)
+        .arg("-C")
+        .arg(dir)
+        .arg(".")
+        .status()
+        .map_err(|e| failed(format!("cannot run tar: {}", e)))?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(failed(format!("tar exited with {}", status)))
+    }
+}
+
+/// Collects the bundle and writes it to `output`, by default
+/// `acp-diagnose-<time>.tar.gz` in the current directory, and returns its path.
+pub async fn create_bundle(
+    output: Option<PathBuf>,
+    log_lines: usize,
+    timeout: Option<u64>,
+) -> Result<PathBuf> {
+    let config = Config::load()?.with_request_timeout(timeout);
+    let clients = config.http_clients()?;
+    let network = NetworkIdentity::current();
+    let mut redactor = Redactor::default();
+    if let Ok((username, password)) = credentials::get_credentials(&network.names()) {
+        redactor.add_token(&username);
+        redactor.add(password.expose_secret());
+    }
+    for name in network.names() {
+        redactor.add_token(name);
+    }
+
+    let mut files: Vec<(&str, String)> = vec![("system.txt", system_info())];
+
+    println!("Probing {}...", config.connectivity_probe.url);
+    let mut extractors = String::new();
+    let mut pages = Vec::new();
+    match fetch_page(&clients.probe, &config.connectivity_probe.url).await {
+        Ok(page) => {
+            describe_page(
+                &mut extractors,
+                "Probe response",
+                &page,
+                &config,
+                &mut redactor,
+            );
+            pages.push(("probe.html", page.html));
+        }
+        Err(e) => {
+            let _ = writeln!(extractors, "== Probe response ==\nFailed: {}\n", e);
+        }
+    }
+
+    let detected = captive_portal::detect_with_probes(
+        &config.connectivity_probes(),
+        &config.http_options(),
+        &clients.probe,
+    )
+    .await;
+    let _ = writeln!(extractors, "== Detection ==");
+    match &detected {
+        Ok(Some(portal)) => {
+            let _ = writeln!(
+                extractors,
+                "Portal at {} ({})\n",
+                portal.url(),
+                portal.kind()
+            );
+        }
+        Ok(None) => {
+            let _ = writeln!(extractors, "No portal: the probe passed\n");
+        }
+        Err(e) => {
+            let _ = writeln!(extractors, "Failed: {}\n", e);
+        }
+    }
+    if let Ok(Some(portal)) = &detected {
+        if !matches!(
+            portal.kind(),
+            PortalKind::BasicAuth | PortalKind::DigestAuth
+        ) {
+            println!("Fetching the portal page...");
+            match fetch_page(&clients.portal, portal.url()).await {
+                Ok(page) => {
+                    describe_page(
+                        &mut extractors,
+                        "Portal page",
+                        &page,
+                        &config,
+                        &mut redactor,
+                    );
+                    pages.push(("portal.html", page.html));
+                }
+                Err(e) => {
+                    let _ = writeln!(extractors, "== Portal page ==\nFailed: {}\n", e);
+                }
+            }
+        }
+    }
+    files.push(("extractors.txt", extractors));
+    files.extend(pages);
+
+    println!("Running the health checks...");
+    let health = health::collect(timeout).await;
+    files.push(("health.json", serde_json::to_string_pretty(&health)?));
+    files.push(("acp.log", last_log_lines(log_lines)));
+
+    let name = format!(
+        "acp-diagnose-{}",
+        chrono::Local::now().format("%Y%m%d-%H%M%S")
+    );
+    // A fresh directory only this user can read, removed when it is dropped,
+    // whether or not the archive could be written.
+    let dir = tempfile::Builder::new()
+        .prefix(&format!("{}-", name))
+        .tempdir()?;
+    for (file, contents) in &files {
+        fs::write(dir.path().join(file), redactor.apply(contents))?;
+    }
+
+    let output = output.unwrap_or_else(|| PathBuf::from(format!("{}.tar.gz", name)));
+    archive(dir.path(), &output)?;
+    Ok(output)
+}
+
+/// `acp-script diagnose`: writes the bundle and tells the user what to do with
+/// it.
+pub async fn run(output: Option<String>, log_lines: usize, timeout: Option<u64>) -> Result<()> {
+    let bundle = create_bundle(output.map(PathBuf::from), log_lines, timeout).await?;
+    println!();
+    println!("Diagnostic bundle written to {}", bundle.display());
+    println!(
+        "It holds system.txt, extractors.txt, health.json, acp.log and the probe and portal \
+         pages. The password, username, magic values, hidden form values, network names and URL \
+         queries are replaced by [redacted]; nothing was uploaded."
+    );
+    println!(
+        "Please look through it before attaching it to an issue at {}",
+        daemon::ISSUES_URL
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn masks_secrets_whatever_their_length_but_tokens_only_when_long() {
+        let mut redactor = Redactor::default();
+        redactor.add("pw");
+        redactor.add_token("abc");
+        redactor.add_token("csrf-token-1");
+        assert_eq!(
+            redactor.apply("user pw, step abc, token csrf-token-1"),
+            "user [redacted], step abc, token [redacted]"
+        );
+    }
+
+    #[test]
+    fn drops_url_queries() {
+        let redactor = Redactor::default();
+        assert_eq!(
+            redactor.apply(r#"<a href="http://10.0.0.1:1000/fgtauth?0a1b2c">"#),
+            r#"<a href="http://10.0.0.1:1000/fgtauth?[redacted]">"#
+        );
+        assert_eq!(
+            redactor.apply("Location: /login?m=1&next=x"),
+            "Location: /login?[redacted]"
+        );
+        assert_eq!(redactor.apply("Is it online?"), "Is it online?");
+    }
+}
@@ -1,10 +1,9 @@
+use reqwest::StatusCode;
+use std::path::PathBuf;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum AppError {
-    #[error("Browser error: {0}")]
-    Browser(String),
-
     #[error("Keyring error: {0}")]
     Keyring(#[from] keyring::Error),
 
@@ -17,8 +16,111 @@ pub enum AppError {
     #[error("Login failed: {0}")]
     LoginFailed(String),
 
-    #[error("Service error: {0}")]
-    Service(String),
+    #[error("Credentials rejected: {0}")]
+    CredentialsRejected(String),
+
+    #[error("Portal at {url} unreachable: {message}")]
+    PortalUnreachable { url: String, message: String },
+
+    #[error("No internet access after {0}")]
+    VerificationFailed(String),
+
+    #[error("Login failed after {attempts} attempts: {source}")]
+    RetriesExhausted {
+        attempts: u32,
+        source: Box<AppError>,
+    },
+
+    #[error("Portal at {url} responded with {status}")]
+    Portal { url: String, status: StatusCode },
+
+    #[error(
+        "Could not resolve {host}; the network may be behind a captive portal that intercepts DNS"
+    )]
+    DnsResolution { host: String },
+
+    #[error("Portal check and login did not finish within {secs}s")]
+    CheckTimedOut { secs: u64 },
+
+    #[error("No credentials found; run `acp-script setup`, or set ACP_USERNAME and ACP_PASSWORD")]
+    CredentialsNotFound,
+
+    #[error("Could not read the credentials: {0}")]
+    CredentialsInput(String),
+
+    #[error("Credentials file {}: {message}", path.display())]
+    CredentialsFile { path: PathBuf, message: String },
+
+    #[error("{command} failed: {message}")]
+    ServiceControl {
+        command: &'static str,
+        message: String,
+    },
+
+    #[error("State file {}: {message}", path.display())]
+    StatePersistence { path: PathBuf, message: String },
+
+    #[error(
+        "Another daemon is already running{}",
+        pid.map(|pid| format!(" (PID {})", pid)).unwrap_or_default()
+    )]
+    AlreadyRunning { pid: Option<u32> },
+
+    #[error("Home directory not found")]
+    HomeDirNotFound,
+
+    #[error("Failed to initialize logging: {0}")]
+    Logging(String),
+
+    #[error("Notification failed: {0}")]
+    Notification(String),
+
+    #[error("Failed to serialize JSON: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("Config error: {0}")]
+    Config(String),
 }
 
 pub type Result<T> = std::result::Result<T, AppError>;
+
+impl AppError {
+    /// Whether the portal turned down the credentials themselves, as opposed
+    /// to a network or portal error.
+    pub fn is_credential_rejection(&self) -> bool {
+        match self {
+            AppError::RetriesExhausted { source, .. } => source.is_credential_rejection(),
+            AppError::CredentialsRejected(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Process exit code for this error, as documented in `--help`.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            AppError::RetriesExhausted { source, .. } => source.exit_code(),
+            AppError::Keyring(_)
+            | AppError::CredentialsRejected(_)
+            | AppError::CredentialsNotFound
+            | AppError::CredentialsInput(_)
+            | AppError::CredentialsFile { .. } => 10,
+            // The portal took the login but misbehaved, or gave no connectivity.
+            AppError::LoginFailed(_)
+            | AppError::VerificationFailed(_)
+            | AppError::Network(_)
+            | AppError::Portal { .. }
+            | AppError::PortalUnreachable { .. }
+            | AppError::DnsResolution { .. }
+            | AppError::CheckTimedOut { .. } => 11,
+            AppError::ServiceControl { .. } => 12,
+            AppError::Config(_) => 13,
+            AppError::Io(_)
+            | AppError::StatePersistence { .. }
+            | AppError::AlreadyRunning { .. }
+            | AppError::HomeDirNotFound
+            | AppError::Logging(_)
+            | AppError::Notification(_)
+            | AppError::Json(_) => 1,
+        }
+    }
+}
@@ -0,0 +1,123 @@
+use crate::error::Result;
+use crate::state;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+/// Number of events kept; the file is compacted back to this many once it
+/// holds twice as many.
+pub const MAX_EVENTS: usize = 50;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    CheckStarted,
+    NetworkChanged,
+    Offline,
+    PortalDetected,
+    LoginSucceeded,
+    LoginFailed,
+    CheckFailed,
+    ScheduledRestart,
+    Stopped,
+    PortalChanged,
+    LoginsPaused,
+    FailureAlert,
+}
+
+impl fmt::Display for EventKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            EventKind::CheckStarted => "check started",
+            EventKind::NetworkChanged => "network changed",
+            EventKind::Offline => "offline",
+            EventKind::PortalDetected => "portal detected",
+            EventKind::LoginSucceeded => "login succeeded",
+            EventKind::LoginFailed => "login failed",
+            EventKind::CheckFailed => "check failed",
+            EventKind::ScheduledRestart => "scheduled restart",
+            EventKind::Stopped => "stopped",
+            EventKind::PortalChanged => "portal page changed",
+            EventKind::LoginsPaused => "logins paused",
+            EventKind::FailureAlert => "failure alert",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Event {
+    pub timestamp: DateTime<Utc>,
+    pub kind: EventKind,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+pub fn get_events_file_path() -> Result<PathBuf> {
+    Ok(state::data_dir()?.join("events.jsonl"))
+}
+
+/// Events from oldest to newest; unreadable lines are skipped.
+pub fn load_events() -> Result<Vec<Event>> {
+    match fs::read_to_string(get_events_file_path()?) {
+        Ok(contents) => Ok(contents
+            .lines()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .collect()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub fn recent_events(count: usize) -> Vec<Event> {
+    let mut events = load_events().unwrap_or_default();
+    events.drain(..events.len().saturating_sub(count));
+    events
+}
+
+// Appending keeps events from the daemon and a concurrent `check` apart;
+// only the occasional compaction rewrites the file.
+fn append(event: &Event) -> Result<()> {
+    let path = get_events_file_path()?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let line = format!("{}\n", serde_json::to_string(event)?);
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)?
+        .write_all(line.as_bytes())?;
+
+    let events = load_events()?;
+    if events.len() >= 2 * MAX_EVENTS {
+        let mut contents = String::new();
+        for event in &events[events.len() - MAX_EVENTS..] {
+            contents.push_str(&serde_json::to_string(event)?);
+            contents.push('\n');
+        }
+        state::write_atomically(&path, contents.as_bytes())?;
+    }
+    Ok(())
+}
+
+/// Records an event for `acp-script status`; failures are only logged.
+pub fn record(kind: EventKind, detail: Option<&str>) {
+    let event = Event {
+        timestamp: Utc::now(),
+        kind,
+        detail: detail.map(str::to_string),
+    };
+    if let Err(e) = append(&event) {
+        log::debug!("Failed to record {} event: {}", kind, e);
+    }
+}
+
+pub fn remove_events_file() -> Result<()> {
+    match fs::remove_file(get_events_file_path()?) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,227 @@
+use crate::captive_portal;
+use crate::config::Config;
+use crate::credentials::{self, CredentialSource};
+use crate::error::{AppError, Result};
+use crate::network::NetworkIdentity;
+use crate::service;
+use crate::state;
+use crate::BuildInfo;
+use serde::Serialize;
+
+#[derive(Serialize)]
+pub struct HealthCheck {
+    pub name: &'static str,
+    pub ok: bool,
+    pub detail: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remediation: Option<String>,
+}
+
+impl HealthCheck {
+    fn pass(name: &'static str, detail: impl Into<String>) -> Self {
+        Self {
+            name,
+            ok: true,
+            detail: detail.into(),
+            remediation: None,
+        }
+    }
+
+    fn fail(name: &'static str, detail: impl Into<String>, remediation: impl Into<String>) -> Self {
+        Self {
+            name,
+            ok: false,
+            detail: detail.into(),
+            remediation: Some(remediation.into()),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct HealthReport {
+    pub healthy: bool,
+    pub build: BuildInfo,
+    pub checks: Vec<HealthCheck>,
+}
+
+/// Runs every check, even after one fails, so a single run shows all problems.
+pub async fn collect(timeout: Option<u64>) -> HealthReport {
+    let mut checks = Vec::new();
+
+    let config = match Config::load() {
+        Ok(config) => {
+            checks.push(HealthCheck::pass("config", "loaded"));
+            config
+        }
+        Err(e) => {
+            checks.push(HealthCheck::fail(
+                "config",
+                e.to_string(),
+                "Fix the config file; `acp-script --print-config-schema` lists the valid keys. \
+                 The remaining checks use the defaults.",
+            ));
+            Config::default()
+        }
+    }
+    .with_request_timeout(timeout);
+
+    let network = NetworkIdentity::current();
+    let source = credentials::credential_source(&network.names());
+    let paused = state::load_state().is_ok_and(|state| state.logins_paused);
+    checks.push(match &source {
+        Ok(_) if paused => HealthCheck::fail(
+            "credentials",
+            "rejected by the portal, logins paused",
+            "Run `acp-script creds set` with the current password, or `acp-script check` to retry",
+        ),
+        Ok(source) => HealthCheck::pass("credentials", format!("configured ({})", source)),
+        Err(AppError::CredentialsNotFound) => HealthCheck::fail(
+            "credentials",
+            "not found",
+            "Run `acp-script creds set`, or set ACP_USERNAME and ACP_PASSWORD",
+        ),
+        Err(e) => HealthCheck::fail(
+            "credentials",
+            e.to_string(),
+            "Run `acp-script creds set`, or set ACP_USERNAME and ACP_PASSWORD",
+        ),
+    });
+
+    checks.push(match credentials::check_keyring() {
+        Ok(()) => HealthCheck::pass("keyring", "available"),
+        Err(e) => match source {
+            Ok(source @ (CredentialSource::Environment | CredentialSource::File)) => {
+                HealthCheck::pass("keyring", format!("unavailable, using the {}", source))
+            }
+            _ => HealthCheck::fail(
+                "keyring",
+                e.to_string(),
+                "Start a Secret Service provider such as gnome-keyring, or run \
+                 `acp-script creds set` to store the credentials in a file instead",
+            ),
+        },
+    });
+
+    checks.extend(network_checks(&config).await);
+
+    checks.push(if service::is_service_installed() {
+        HealthCheck::pass("service_installed", "installed")
+    } else {
+        HealthCheck::fail(
+            "service_installed",
+            "not installed",
+            "Run `acp-script setup`",
+        )
+    });
+    checks.push(if service::is_service_running() {
+        HealthCheck::pass("service_running", "running")
+    } else {
+        HealthCheck::fail(
+            "service_running",
+            "not running",
+            "Run `acp-script restart` and check `acp-script logs` for errors",
+        )
+    });
+
+    HealthReport {
+        healthy: checks.iter().all(|check| check.ok),
+        build: crate::BUILD,
+        checks,
+    }
+}
+
+async fn network_checks(config: &Config) -> Vec<HealthCheck> {
+    let clients = match config.http_clients() {
+        Ok(clients) => clients,
+        Err(e) => {
+            return vec![HealthCheck::fail(
+                "portal",
+                e.to_string(),
+                "Check the proxy and user_agent settings in the config file",
+            )]
+        }
+    };
+
+    let portal = match captive_portal::detect_with_probes(
+        &config.connectivity_probes(),
+        &config.http_options(),
+        &clients.probe,
+    )
+    .await
+    {
+        Ok(None) => HealthCheck::pass("portal", "none detected"),
+        Ok(Some(portal)) => HealthCheck::fail(
+            "portal",
+            format!("login required at {}", portal.url()),
+            "Run `acp-script check` to log in, or check `acp-script logs` if the service \
+             should have done so",
+        ),
+        Err(e @ AppError::DnsResolution { .. }) => HealthCheck::fail(
+            "portal",
+            e.to_string(),
+            "Set connectivity_probe.fallback_ips so the portal can be detected without DNS",
+        ),
+        Err(e) => HealthCheck::fail(
+            "portal",
+            e.to_string(),
+            "Check the connectivity_probe settings and the network connection",
+        ),
+    };
+
+    let connectivity =
+        captive_portal::check_connectivity(&config.connectivity_probe, &config.http_options())
+            .await;
+    let connectivity = match connectivity {
+        Ok(connectivity) if connectivity.v4 || connectivity.v6 => {
+            HealthCheck::pass("connectivity", "online")
+        }
+        Ok(_) => HealthCheck::fail(
+            "connectivity",
+            "offline",
+            "Check the network connection; behind a portal, log in first",
+        ),
+        Err(e) => HealthCheck::fail(
+            "connectivity",
+            e.to_string(),
+            "Fix connectivity_probe.url in the config file",
+        ),
+    };
+
+    vec![connectivity, portal]
+}
+
+fn print_report(report: &HealthReport) {
+    println!("Auto Captive Portal health");
+    println!("  {}", report.build);
+    for check in &report.checks {
+        println!(
+            "  [{}] {:<18} {}",
+            if check.ok { " ok " } else { "FAIL" },
+            check.name,
+            check.detail
+        );
+        if let Some(remediation) = &check.remediation {
+            println!("         {:<18} {}", "", remediation);
+        }
+    }
+
+    let failed = report.checks.iter().filter(|check| !check.ok).count();
+    if failed == 0 {
+        println!("All {} checks passed", report.checks.len());
+    } else {
+        println!("{} of {} checks failed", failed, report.checks.len());
+    }
+}
+
+/// Prints the report and returns whether every check passed.
+pub async fn show_health(json: bool, timeout: Option<u64>) -> Result<bool> {
+    let report = collect(timeout).await;
+
+    if json {
+        let output = serde_json::to_string_pretty(&report)?;
+        println!("{}", output);
+    } else {
+        print_report(&report);
+    }
+    Ok(report.healthy)
+}
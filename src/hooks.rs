@@ -0,0 +1,64 @@
+use crate::captive_portal::{self, HttpOptions};
+use serde::Serialize;
+use std::time::Duration;
+
+const HOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HookEvent {
+    Login,
+    Failure,
+    /// Sent once when `failure_alert_threshold` checks in a row failed.
+    FailureAlert,
+    /// Sent by `acp-script test-notification`, never by the daemon.
+    Test,
+}
+
+#[derive(Serialize)]
+struct HookPayload<'a> {
+    event: HookEvent,
+    ssid: Option<&'a str>,
+    /// Without the query, which carries the magic value.
+    portal_url: Option<String>,
+    timestamp: String,
+}
+
+pub async fn send_hook(
+    url: Option<&str>,
+    http: &HttpOptions,
+    event: HookEvent,
+    ssid: Option<&str>,
+    portal_url: Option<&str>,
+) {
+    let Some(url) = url else {
+        return;
+    };
+
+    let payload = HookPayload {
+        event,
+        ssid,
+        portal_url: portal_url.map(captive_portal::shareable_portal_url),
+        timestamp: chrono::Utc::now().to_rfc3339(),
+    };
+
+    let http = HttpOptions {
+        timeout: HOOK_TIMEOUT,
+        ..http.clone()
+    };
+    let client = match captive_portal::build_http_client(&http) {
+        Ok(client) => client,
+        Err(e) => {
+            log::error!("Webhook client setup failed: {}", e);
+            return;
+        }
+    };
+
+    match client.post(url).json(&payload).send().await {
+        Ok(resp) if !resp.status().is_success() => {
+            log::warn!("Webhook {} returned {}", url, resp.status());
+        }
+        Ok(_) => {}
+        Err(e) => log::warn!("Webhook {} failed: {}", url, e),
+    }
+}
@@ -0,0 +1,55 @@
+//! Captive portal detection and login, usable as a library by other
+//! programs as well as by the `acp-script` binary.
+
+/// Name of the background service, and the keyring service the credentials
+/// are stored under unless `keyring_service` is set in the config.
+pub const SERVICE_NAME: &str = if cfg!(target_os = "macos") {
+    "com.user.acp"
+} else {
+    "acp"
+};
+
+/// Identifies the build, for `acp-script version` and `health --json`, so
+/// bug reports and dashboards can tell deployed versions apart.
+#[derive(Debug, Clone, Copy, serde::Serialize)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    pub target: &'static str,
+    /// Git commit the binary was built from, if built from a checkout.
+    pub commit: Option<&'static str>,
+}
+
+pub const BUILD: BuildInfo = BuildInfo {
+    version: env!("CARGO_PKG_VERSION"),
+    target: env!("ACP_TARGET"),
+    commit: option_env!("ACP_GIT_COMMIT"),
+};
+
+impl std::fmt::Display for BuildInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "acp-script {} ({}", self.version, self.target)?;
+        if let Some(commit) = self.commit {
+            write!(f, ", commit {}", commit)?;
+        }
+        write!(f, ")")
+    }
+}
+
+mod backoff;
+pub mod captive_portal;
+pub mod config;
+pub mod credentials;
+pub mod daemon;
+pub mod diagnose;
+pub mod error;
+pub mod events;
+pub mod health;
+pub mod hooks;
+pub mod logging;
+mod network;
+pub mod notifications;
+pub mod service;
+pub mod state;
+pub mod status;
+#[cfg(feature = "status-page")]
+pub mod status_page;
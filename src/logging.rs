@@ -0,0 +1,486 @@
+use crate::error::{AppError, Result};
+use crate::state;
+use log::LevelFilter;
+use schemars::JsonSchema;
+use secrecy::{ExposeSecret, SecretString};
+use serde::Deserialize;
+use std::borrow::Cow;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{PoisonError, RwLock};
+
+const LOG_FILE_NAME: &str = "acp.log";
+const REDACTED: &str = "[redacted]";
+const MIN_SECRET_LEN: usize = 4;
+
+/// Values masked in every log line: the password and the portal's magic value.
+static SECRETS: RwLock<Vec<SecretString>> = RwLock::new(Vec::new());
+
+#[cfg(unix)]
+const SYSLOG_SOCKETS: [&str; 3] = ["/dev/log", "/var/run/syslog", "/var/run/log"];
+#[cfg(unix)]
+const SYSLOG_IDENT: &str = "acp";
+#[cfg(unix)]
+const SYSLOG_FACILITY_DAEMON: u8 = 3;
+
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// Size in bytes at which the log file is rotated.
+    pub max_file_size_bytes: u64,
+    /// Number of rotated log files (`acp.log.1`, `acp.log.2`, ...) to keep.
+    pub max_backups: usize,
+    /// Line format of the daemon's console and file logs.
+    pub format: LogFormat,
+    /// Stream the daemon's and `check`'s console logs go to; `stderr` keeps
+    /// stdout free for piping.
+    pub console: ConsoleStream,
+    /// Also send the daemon's logs to the local syslog/journald with the ident
+    /// `acp` (Linux and macOS).
+    pub syslog: bool,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    /// `[2024-01-01 12:00:00 INFO] message`
+    #[default]
+    Text,
+    /// One JSON object per line with `timestamp`, `level`, `target` and
+    /// `message`.
+    Json,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ConsoleStream {
+    #[default]
+    Stdout,
+    Stderr,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            max_file_size_bytes: 5 * 1024 * 1024,
+            max_backups: 3,
+            format: LogFormat::default(),
+            console: ConsoleStream::default(),
+            syslog: false,
+        }
+    }
+}
+
+pub fn get_log_file_path() -> Result<PathBuf> {
+    Ok(state::data_dir()?.join("logs").join(LOG_FILE_NAME))
+}
+
+fn backup_path(path: &Path, index: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", index));
+    PathBuf::from(name)
+}
+
+pub struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    max_backups: usize,
+    file: File,
+    size: u64,
+    at_line_start: bool,
+}
+
+impl RotatingFile {
+    pub fn open(path: PathBuf, max_size: u64, max_backups: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            max_size,
+            max_backups,
+            file,
+            size,
+            at_line_start: true,
+        })
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.max_backups == 0 {
+            self.file = File::create(&self.path)?;
+        } else {
+            for index in (1..self.max_backups).rev() {
+                let from = backup_path(&self.path, index);
+                if from.exists() {
+                    fs::rename(&from, backup_path(&self.path, index + 1))?;
+                }
+            }
+            fs::rename(&self.path, backup_path(&self.path, 1))?;
+            self.file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+        }
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Only rotate between lines so a record is never split across files.
+        if self.at_line_start && self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        if written > 0 {
+            self.at_line_start = buf[written - 1] == b'\n';
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+fn open_log_file(config: &LoggingConfig) -> Result<RotatingFile> {
+    let path = get_log_file_path()?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    Ok(RotatingFile::open(
+        path,
+        config.max_file_size_bytes,
+        config.max_backups,
+    )?)
+}
+
+fn env_level() -> Option<LevelFilter> {
+    std::env::var("RUST_LOG").ok()?.trim().parse().ok()
+}
+
+/// Masks `secret` wherever it appears in later log lines, on every output.
+/// Values shorter than 4 characters are left alone: masking them would mangle
+/// every line while hiding next to nothing.
+pub fn redact_secret(secret: &str) {
+    if secret.chars().count() < MIN_SECRET_LEN {
+        return;
+    }
+    let mut secrets = SECRETS.write().unwrap_or_else(PoisonError::into_inner);
+    if !secrets.iter().any(|known| known.expose_secret() == secret) {
+        secrets.push(SecretString::from(secret));
+        // Longest first, so a secret containing another is masked whole.
+        secrets.sort_by_key(|known| std::cmp::Reverse(known.expose_secret().len()));
+    }
+}
+
+/// `line` with every value passed to `redact_secret` replaced by `[redacted]`.
+pub fn redact(line: &str) -> Cow<'_, str> {
+    let secrets = SECRETS.read().unwrap_or_else(PoisonError::into_inner);
+    let mut line = Cow::Borrowed(line);
+    for secret in secrets.iter() {
+        if line.contains(secret.expose_secret()) {
+            line = Cow::Owned(line.replace(secret.expose_secret(), REDACTED));
+        }
+    }
+    line
+}
+
+fn formatted_dispatch(format: LogFormat) -> fern::Dispatch {
+    match format {
+        LogFormat::Text => fern::Dispatch::new().format(|out, message, record| {
+            out.finish(format_args!(
+                "[{} {}] {}",
+                chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
+                record.level(),
+                redact(&message.to_string())
+            ))
+        }),
+        LogFormat::Json => fern::Dispatch::new().format(|out, message, record| {
+            let line = serde_json::json!({
+                "timestamp": chrono::Local::now().to_rfc3339(),
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "message": redact(&message.to_string()),
+            });
+            out.finish(format_args!("{}", line))
+        }),
+    }
+}
+
+// Minimal RFC 3164 sender for the local syslog socket; journald listens on
+// /dev/log too, so `journalctl -t acp` picks these up.
+#[cfg(unix)]
+fn syslog_output() -> io::Result<fern::Output> {
+    use log::Level;
+    use std::os::unix::net::UnixDatagram;
+
+    let socket = UnixDatagram::unbound()?;
+    if !SYSLOG_SOCKETS
+        .iter()
+        .any(|path| socket.connect(path).is_ok())
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "no syslog socket found",
+        ));
+    }
+
+    let pid = std::process::id();
+    Ok(fern::Output::call(move |record| {
+        let severity = match record.level() {
+            Level::Error => 3,
+            Level::Warn => 4,
+            Level::Info => 6,
+            Level::Debug | Level::Trace => 7,
+        };
+        let line = format!(
+            "<{}>{}[{}]: {}",
+            SYSLOG_FACILITY_DAEMON * 8 + severity,
+            SYSLOG_IDENT,
+            pid,
+            redact(&record.args().to_string())
+        );
+        let _ = socket.send(line.as_bytes());
+    }))
+}
+
+/// Logs to the console and the log file. `console_level` overrides the
+/// console level only; the file follows `RUST_LOG`, defaulting to info.
+pub fn init_logging(config: &LoggingConfig, console_level: Option<LevelFilter>) -> Result<()> {
+    let file_level = env_level().unwrap_or(LevelFilter::Info);
+    let console_level = console_level.unwrap_or(file_level);
+    let console: fern::Output = match config.console {
+        ConsoleStream::Stdout => io::stdout().into(),
+        ConsoleStream::Stderr => io::stderr().into(),
+    };
+
+    let mut dispatch = fern::Dispatch::new()
+        .level(console_level.max(file_level))
+        .chain(
+            formatted_dispatch(config.format)
+                .level(console_level)
+                .chain(console),
+        );
+
+    let file_error = match open_log_file(config) {
+        Ok(file) => {
+            dispatch = dispatch.chain(
+                formatted_dispatch(config.format)
+                    .level(file_level)
+                    .chain(Box::new(file) as Box<dyn Write + Send>),
+            );
+            None
+        }
+        Err(e) => Some(e),
+    };
+
+    // Syslog adds its own timestamp and severity, so it gets the bare message.
+    #[cfg(unix)]
+    let syslog_error = match config.syslog.then(syslog_output) {
+        Some(Ok(output)) => {
+            dispatch = dispatch.chain(fern::Dispatch::new().level(file_level).chain(output));
+            None
+        }
+        Some(Err(e)) => Some(e),
+        None => None,
+    };
+
+    dispatch
+        .apply()
+        .map_err(|e| AppError::Logging(e.to_string()))?;
+    if let Some(e) = file_error {
+        log::warn!("Logging to the console only, log file unavailable: {}", e);
+    }
+    #[cfg(unix)]
+    if let Some(e) = syslog_error {
+        log::warn!("Syslog unavailable: {}", e);
+    }
+    #[cfg(not(unix))]
+    if config.syslog {
+        log::warn!("syslog is only supported on Linux and macOS");
+    }
+    Ok(())
+}
+
+/// Logs to stderr only, for interactive commands that keep stdout for their
+/// own output. Without `level` this follows `RUST_LOG`, and logs nothing when
+/// that is unset too.
+pub fn init_console_logging(level: Option<LevelFilter>) -> Result<()> {
+    let Some(level) = level.or_else(env_level) else {
+        return Ok(());
+    };
+    formatted_dispatch(LogFormat::Text)
+        .level(level)
+        .chain(io::stderr())
+        .apply()
+        .map_err(|e| AppError::Logging(e.to_string()))
+}
+
+fn print_new_bytes(path: &Path, offset: &mut u64) -> io::Result<()> {
+    use std::io::{Read, Seek, SeekFrom};
+
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    if len < *offset {
+        // The file was rotated; start over from the beginning of the new one.
+        *offset = 0;
+    }
+    file.seek(SeekFrom::Start(*offset))?;
+    let mut buf = Vec::new();
+    file.read_to_end(&mut buf)?;
+    *offset += buf.len() as u64;
+
+    let mut stdout = io::stdout().lock();
+    stdout.write_all(&buf)?;
+    stdout.flush()
+}
+
+pub async fn show_logs(lines: usize, follow: bool) -> Result<()> {
+    let path = get_log_file_path()?;
+    let mut offset = 0;
+
+    match fs::read(&path) {
+        Ok(contents) => {
+            offset = contents.len() as u64;
+            let text = String::from_utf8_lossy(&contents);
+            let all: Vec<&str> = text.lines().collect();
+            for line in &all[all.len().saturating_sub(lines)..] {
+                println!("{}", line);
+            }
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            println!("No log file yet at {}", path.display());
+        }
+        Err(e) => return Err(e.into()),
+    }
+
+    if !follow {
+        return Ok(());
+    }
+
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(std::time::Duration::from_millis(500)) => {}
+            _ = tokio::signal::ctrl_c() => return Ok(()),
+        }
+        match print_new_bytes(&path, &mut offset) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    /// Runs one record through the `format` dispatch and returns the line.
+    fn format_record(format: LogFormat, message: &str) -> String {
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let sink = lines.clone();
+        let (_, logger) = formatted_dispatch(format)
+            .chain(fern::Output::call(move |record| {
+                sink.lock().unwrap().push(record.args().to_string())
+            }))
+            .into_log();
+        logger.log(
+            &log::Record::builder()
+                .args(format_args!("{}", message))
+                .level(log::Level::Info)
+                .target("acp_script::daemon")
+                .build(),
+        );
+        let line = lines.lock().unwrap().pop().unwrap();
+        line
+    }
+
+    #[test]
+    fn redacts_registered_secrets() {
+        redact_secret("correct-horse-7");
+        redact_secret("a1b2c3d4e5f6");
+        assert_eq!(
+            redact("login with correct-horse-7 at /fgtauth?a1b2c3d4e5f6"),
+            "login with [redacted] at /fgtauth?[redacted]"
+        );
+        assert!(matches!(redact("nothing secret here"), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn ignores_short_secrets() {
+        redact_secret("pw1");
+        assert_eq!(redact("pw1 stays"), "pw1 stays");
+    }
+
+    #[test]
+    fn masks_a_secret_containing_another_whole() {
+        redact_secret("s3cret");
+        redact_secret("s3cret-and-more");
+        assert_eq!(
+            redact("password=s3cret-and-more, old=s3cret"),
+            "password=[redacted], old=[redacted]"
+        );
+    }
+
+    #[test]
+    fn redacts_text_and_json_records() {
+        redact_secret("tr0ub4dor&3");
+        let text = format_record(LogFormat::Text, "Submitting password tr0ub4dor&3");
+        assert!(
+            text.ends_with("INFO] Submitting password [redacted]"),
+            "{}",
+            text
+        );
+
+        let json = format_record(LogFormat::Json, "Submitting password tr0ub4dor&3");
+        assert!(!json.contains("tr0ub4dor"), "{}", json);
+        let json: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(json["message"], "Submitting password [redacted]");
+    }
+
+    #[test]
+    fn rotates_and_drops_the_oldest_backup() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(LOG_FILE_NAME);
+        let mut file = RotatingFile::open(path.clone(), 8, 2).unwrap();
+
+        file.write_all(b"first\n").unwrap();
+        assert!(!backup_path(&path, 1).exists());
+        file.write_all(b"second\n").unwrap();
+        assert_eq!(
+            fs::read_to_string(backup_path(&path, 1)).unwrap(),
+            "first\n"
+        );
+
+        file.write_all(b"third\n").unwrap();
+        file.write_all(b"fourth\n").unwrap();
+        file.flush().unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fourth\n");
+        assert_eq!(
+            fs::read_to_string(backup_path(&path, 1)).unwrap(),
+            "third\n"
+        );
+        assert_eq!(
+            fs::read_to_string(backup_path(&path, 2)).unwrap(),
+            "second\n"
+        );
+        assert!(!backup_path(&path, 3).exists());
+    }
+
+    #[test]
+    fn never_splits_a_line_across_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(LOG_FILE_NAME);
+        let mut file = RotatingFile::open(path.clone(), 4, 1).unwrap();
+
+        file.write_all(b"a long ").unwrap();
+        file.write_all(b"line\n").unwrap();
+        file.flush().unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "a long line\n");
+        assert!(!backup_path(&path, 1).exists());
+    }
+}
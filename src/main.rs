@@ -1,25 +1,24 @@
-mod captive_portal;
-mod error;
-mod notifications;
-mod service;
-
-use error::{AppError, Result};
-use keyring::Entry;
-use service::{ServiceManager, SERVICE_NAME};
+mod cli;
+
+use acp_script::config::{self, Config};
+use acp_script::credentials::CredentialStore;
+use acp_script::daemon::CheckOutcome;
+use acp_script::error::{AppError, Result};
+use acp_script::service::{self, ServiceManager};
+use acp_script::{
+    captive_portal, credentials, daemon, diagnose, events, health, logging, notifications, state,
+    status,
+};
+use cli::{Command, CredsAction};
+use log::LevelFilter;
+use secrecy::zeroize::Zeroize;
+use secrecy::{ExposeSecret, SecretString};
 use std::{
     env,
-    io::{self, Write},
+    io::{self, IsTerminal, Write},
+    time::Duration,
 };
 
-fn get_credentials() -> Result<(String, String)> {
-    let username_entry = Entry::new(SERVICE_NAME, "ldap_username").map_err(AppError::from)?;
-    let password_entry = Entry::new(SERVICE_NAME, "ldap_password").map_err(AppError::from)?;
-    Ok((
-        username_entry.get_password().map_err(AppError::from)?,
-        password_entry.get_password().map_err(AppError::from)?,
-    ))
-}
-
 fn prompt_input(prompt: &str) -> std::result::Result<String, std::io::Error> {
     print!("{}", prompt);
     io::stdout().flush()?;
@@ -28,62 +27,452 @@ fn prompt_input(prompt: &str) -> std::result::Result<String, std::io::Error> {
     Ok(input.trim().to_string())
 }
 
-async fn setup() -> Result<()> {
-    println!("Setting up Auto Captive Portal...");
+fn prompt_secret(prompt: &str) -> std::result::Result<SecretString, std::io::Error> {
+    print!("{}", prompt);
+    io::stdout().flush()?;
+    // Preallocate so read_line doesn't leave reallocated copies behind.
+    let mut input = String::with_capacity(256);
+    let result = io::stdin().read_line(&mut input);
+    let secret = SecretString::from(input.trim());
+    input.zeroize();
+    result?;
+    Ok(secret)
+}
+
+/// Asks for the username and password. When stdin isn't a terminal, e.g. in
+/// automated provisioning, they come from `ACP_USERNAME` and `ACP_PASSWORD`
+/// if both are set, or else from the first two lines piped in.
+fn read_credentials() -> Result<(String, SecretString)> {
+    let interactive = io::stdin().is_terminal();
+    if !interactive {
+        if let (Ok(username), Ok(password)) = (
+            env::var(credentials::USERNAME_ENV),
+            env::var(credentials::PASSWORD_ENV),
+        ) {
+            println!(
+                "Using the credentials from {} and {}",
+                credentials::USERNAME_ENV,
+                credentials::PASSWORD_ENV
+            );
+            return Ok((username, SecretString::from(password)));
+        }
+    }
+
+    let prompt = |text| if interactive { text } else { "" };
+    let username = prompt_input(prompt("Enter LDAP Username: "))?;
+    let password = prompt_secret(prompt("Enter LDAP Password: "))?;
+    if username.is_empty() || password.expose_secret().is_empty() {
+        return Err(AppError::CredentialsInput(if interactive {
+            "the username and password must not be empty".to_string()
+        } else {
+            format!(
+                "stdin is not a terminal and did not hold a username and a password; pipe them \
+                 on two lines, or set {} and {}",
+                credentials::USERNAME_ENV,
+                credentials::PASSWORD_ENV
+            )
+        }));
+    }
+    Ok((username, password))
+}
+
+fn store_credentials(username: &str, password: &SecretString, network: Option<&str>) -> Result<()> {
+    if let CredentialStore::File(path) =
+        credentials::store_credentials(username, password, network)?
+    {
+        println!(
+            "No system keyring available; credentials saved to {} (readable only by you).",
+            path.display()
+        );
+        println!("Install a keyring such as gnome-keyring to store them there instead.");
+    }
 
-    let username = prompt_input("Enter LDAP Username: ").map_err(AppError::from)?;
-    let password = prompt_input("Enter LDAP Password: ").map_err(AppError::from)?;
+    state::update_state_file(|state| {
+        // New credentials may fix whatever the portal rejected.
+        state.resume_logins();
+        if let Some(network) = network {
+            if !state
+                .credential_networks
+                .iter()
+                .any(|known| known == network)
+            {
+                state.credential_networks.push(network.to_string());
+            }
+        }
+    })?;
+    Ok(())
+}
 
-    let executable_path = env::current_exe()?;
-    let service_manager = ServiceManager::new(executable_path);
+async fn setup(network: Option<&str>, start: bool, system: bool) -> Result<()> {
+    let service_manager = ServiceManager::new(env::current_exe()?).with_system_scope(system);
+    service_manager.check_privileges()?;
+
+    if let Some(network) = network {
+        println!("Adding credentials for network {}...", network);
+    } else {
+        println!("Setting up Auto Captive Portal...");
+    }
+
+    let (username, password) = read_credentials()?;
+
+    if system {
+        let path = service::store_system_credentials(&username, &password)?;
+        println!(
+            "Credentials saved to {} (readable only by root).",
+            path.display()
+        );
+    } else {
+        store_credentials(&username, &password, network)?;
+    }
+    if network.is_some() {
+        println!("Network profile saved!");
+        return Ok(());
+    }
 
-    service_manager.store_credentials(&username, &password)?;
     service_manager.create_service()?;
+    if !start {
+        println!(
+            "Service installed but not started. Start it with: {}",
+            service::start_command()?
+        );
+        return Ok(());
+    }
+    service::start_service()?;
 
     println!("Setup completed successfully!");
     Ok(())
 }
 
-async fn run() -> Result<()> {
-    let (username, password) = get_credentials()?;
-
-    loop {
-        match captive_portal::check_captive_portal().await {
-            Ok(Some(url)) => {
-                println!("Captive portal detected at {}", url);
-                if let Err(e) = captive_portal::login(&url, &username, &password).await {
-                    eprintln!("Login failed: {}", e);
-                    service::restart_service().await?;
-                } else {
-                    notifications::send_notification(
-                        "Captive portal detected and logged in successfully",
-                    )
-                    .await;
+fn uninstall() -> Result<()> {
+    println!("Uninstalling Auto Captive Portal...");
+
+    service::remove_service()?;
+
+    let state = state::load_state().unwrap_or_default();
+    for network in &state.credential_networks {
+        credentials::clear_credentials(Some(network))?;
+    }
+    credentials::clear_credentials(None)?;
+    state::remove_state_file()?;
+    events::remove_events_file()?;
+
+    println!("Uninstall completed successfully!");
+    Ok(())
+}
+
+async fn logout() -> Result<()> {
+    let config = Config::load()?;
+    let clients = config.http_clients()?;
+    let portal_url = state::load_state()?.last_portal_detected;
+
+    let logout_url = match (
+        &config.logout_url,
+        portal_url
+            .as_deref()
+            .and_then(captive_portal::default_logout_url),
+    ) {
+        (Some(url), _) => url.clone(),
+        (None, Some(url)) => url.to_string(),
+        (None, None) => {
+            return Err(AppError::Config(
+                "No portal login recorded yet; set logout_url in the config file".into(),
+            ))
+        }
+    };
+    let magic = portal_url
+        .as_deref()
+        .and_then(captive_portal::magic_from_portal_url);
+
+    println!("Logging out via {}", logout_url);
+    captive_portal::logout(&clients.portal, &logout_url, magic.as_deref()).await?;
+
+    if captive_portal::verify_internet_connectivity(
+        &config.connectivity_probe,
+        &clients.probe,
+        None,
+    )
+    .await?
+    {
+        println!("Logout request sent, but internet access is still available");
+    } else {
+        println!("Logged out: internet access revoked");
+    }
+    Ok(())
+}
+
+fn test_portal(file: &str, url: Option<&str>) -> Result<()> {
+    const PLACEHOLDER_URL: &str = "http://portal.invalid/";
+
+    let config = Config::load()?;
+    let html = std::fs::read_to_string(file)?;
+    let page_url = reqwest::Url::parse(url.unwrap_or(PLACEHOLDER_URL))
+        .map_err(|e| AppError::Config(format!("Invalid --url: {}", e)))?;
+    if url.is_none() {
+        println!(
+            "No --url given; relative links are shown against {}",
+            PLACEHOLDER_URL
+        );
+    }
+
+    let redirect = captive_portal::extract_configured_portal_url(&html)
+        .map(|target| (target, "portal_url_pattern"))
+        .or_else(|| {
+            captive_portal::extract_javascript_redirect_url(&html)
+                .map(|target| (target, "JavaScript redirect"))
+        })
+        .or_else(|| {
+            captive_portal::extract_meta_refresh_url(&html).map(|target| (target, "meta refresh"))
+        });
+    let page = captive_portal::parse_login_page(&html, &page_url);
+    match redirect {
+        Some((target, kind)) => {
+            let target = page_url
+                .join(&target)
+                .map_or(target, |target| target.to_string());
+            println!("Portal redirect ({}): {}", kind, target);
+            match captive_portal::magic_from_portal_url(&target) {
+                Some(magic) => println!("Magic value: {}", magic),
+                None => println!("Magic value: none in the redirect URL"),
+            }
+            if !page.layout.has_password_field {
+                println!(
+                    "The login page is fetched from that URL; save it too to test the login form."
+                );
+                return Ok(());
+            }
+        }
+        None => println!("Portal redirect: none found"),
+    }
+
+    let options = config.form_options();
+    if let (false, Some(step)) = (page.layout.has_password_field, &options.pre_auth) {
+        match captive_portal::find_pre_auth_target(&html, &page_url, step) {
+            Some(target) => {
+                println!("Pre-auth page: the login would {}", target);
+                if let captive_portal::PreAuthTarget::Form { fields, .. } = &target {
+                    for (name, value) in fields {
+                        println!("  {} = {:?}", name, value);
+                    }
                 }
+                println!("Save the page that leads to as well to test the login form.");
+                return Ok(());
             }
-            Ok(None) => println!("No captive portal detected"),
-            Err(e) => {
-                eprintln!("Portal check failed: {}", e);
-                service::restart_service().await?;
+            None => println!("Pre-auth page: no link or form matches pre_auth_step"),
+        }
+    }
+    if !page.layout.has_password_field {
+        println!(
+            "Warning: no password field found; the login would be sent blind to the fields below"
+        );
+    }
+    println!(
+        "Login would be submitted with {} to {}",
+        options.method.resolve(&page.form.method),
+        page.form.action
+    );
+    let mut fields: Vec<_> = page
+        .hidden_fields
+        .iter()
+        .filter(|(name, _)| **name != options.fields.username && **name != options.fields.password)
+        .collect();
+    fields.sort();
+    for (name, value) in fields {
+        println!("  {} = {:?}", name, value);
+    }
+    println!("  {} = <username>", options.fields.username);
+    println!("  {} = <password>", options.fields.password);
+    println!("Page layout hash: {}", page.layout.hash);
+    Ok(())
+}
+
+async fn creds(
+    action: CredsAction,
+    network: Option<&str>,
+    console_level: Option<LevelFilter>,
+) -> Result<()> {
+    match action {
+        CredsAction::Set => {
+            let (username, password) = read_credentials()?;
+            store_credentials(&username, &password, network)?;
+            println!("Credentials updated.");
+
+            // Nobody is there to answer when the credentials were piped in.
+            if !io::stdin().is_terminal() {
+                return Ok(());
+            }
+            let answer = prompt_input("Log in now to test them? [y/N] ").map_err(AppError::from)?;
+            if answer.eq_ignore_ascii_case("y") {
+                let outcome = match run_check(false, console_level, None).await? {
+                    CheckOutcome::LoggedIn => "Logged in with the new credentials.",
+                    CheckOutcome::AlreadyLoggedIn => {
+                        "Already online through the portal, so the credentials were not tested."
+                    }
+                    CheckOutcome::NoPortal => {
+                        "No captive portal right now; the new credentials will be used at the next one."
+                    }
+                    CheckOutcome::Offline => {
+                        "Offline: the connectivity probe's host could not be resolved, so the \
+                         credentials were not tested."
+                    }
+                    CheckOutcome::Skipped => {
+                        // `run_check` resumes paused logins, so only allowed_ssids skips here.
+                        "This network is not in allowed_ssids, so the credentials were not tested."
+                    }
+                    CheckOutcome::DryRun => {
+                        "dry_run is set in the config, so nothing was submitted and the credentials \
+                         were not tested."
+                    }
+                };
+                println!("{}", outcome);
             }
         }
+        CredsAction::Clear => {
+            credentials::clear_credentials(network)?;
+            if let Some(network) = network {
+                state::update_state_file(|state| {
+                    state.credential_networks.retain(|known| known != network)
+                })?;
+            }
+            println!("Credentials removed.");
+        }
+        CredsAction::ShowUser => {
+            let (username, _) = credentials::get_credentials(network.as_slice())?;
+            let source = credentials::credential_source(network.as_slice())?;
+            println!("{} (from the {})", username, source);
+        }
+    }
+    Ok(())
+}
 
-        tokio::time::sleep(tokio::time::Duration::from_secs(10)).await;
+/// Runs one portal check and login, as `check` does.
+async fn run_check(
+    dry_run: bool,
+    console_level: Option<LevelFilter>,
+    timeout: Option<u64>,
+) -> Result<CheckOutcome> {
+    let mut config = Config::load()?.with_request_timeout(timeout);
+    config.dry_run |= dry_run;
+    logging::init_logging(&config.logging, console_level)?;
+    notifications::init(&config);
+    daemon::resume_logins();
+    if service::is_service_running() {
+        // State updates are locked, so both processes keep accurate counters.
+        log::info!("The background service is running too; it will pick up this check's result");
     }
+    daemon::check_with_deadline(&config, &config.http_clients()?).await
+}
+
+/// `check`: the exit code for the outcome of a single check, as documented in `--help`.
+async fn check_once(
+    dry_run: bool,
+    console_level: Option<LevelFilter>,
+    timeout: Option<u64>,
+) -> Result<i32> {
+    Ok(match run_check(dry_run, console_level, timeout).await? {
+        CheckOutcome::LoggedIn | CheckOutcome::DryRun => 0,
+        CheckOutcome::NoPortal | CheckOutcome::Skipped => 2,
+        CheckOutcome::AlreadyLoggedIn => 3,
+        CheckOutcome::Offline => 4,
+    })
 }
 
 #[tokio::main]
 async fn main() {
-    if env::args().nth(1).as_deref() == Some("setup") {
-        if let Err(e) = setup().await {
-            eprintln!("Setup failed: {}", e);
+    let args: Vec<String> = env::args().skip(1).collect();
+    let cli = match cli::parse(&args) {
+        Ok(cli) => cli,
+        Err(e) => {
+            eprintln!("{}\nRun 'acp-script --help' for usage.", e);
             std::process::exit(1);
         }
-        return;
+    };
+
+    // Commands that need the config report its errors themselves.
+    if let Ok(config) = Config::load() {
+        credentials::init(&config);
+        if let Ok(patterns) = config.detection_patterns() {
+            captive_portal::init(patterns);
+        }
     }
 
-    if let Err(e) = run().await {
-        eprintln!("Application error: {}", e);
-        std::process::exit(1);
+    if cli.command.is_interactive() {
+        if let Err(e) = logging::init_console_logging(cli.console_level) {
+            eprintln!("{}", e);
+        }
+    }
+
+    let (context, result) = match cli.command {
+        Command::Daemon => ("Application error", daemon::run(cli.console_level).await),
+        Command::Setup {
+            network,
+            start,
+            system,
+        } => (
+            "Setup failed",
+            setup(network.as_deref(), start, system).await,
+        ),
+        Command::Restart => (
+            "Restart failed",
+            service::restart_service()
+                .await
+                .map(|()| println!("Service restarted")),
+        ),
+        Command::Uninstall => ("Uninstall failed", uninstall()),
+        #[cfg(target_os = "windows")]
+        Command::Service => ("Service failed", service::run_as_windows_service()),
+        Command::Status { json, watch } => (
+            "Status failed",
+            match watch {
+                Some(secs) => status::watch_status(Duration::from_secs(secs), cli.timeout).await,
+                None => status::show_status(json, cli.timeout).await,
+            },
+        ),
+        Command::Health { json } => match health::show_health(json, cli.timeout).await {
+            Ok(healthy) => std::process::exit(if healthy { 0 } else { 5 }),
+            Err(e) => ("Health check failed", Err(e)),
+        },
+        Command::Check { dry_run } => {
+            match check_once(dry_run, cli.console_level, cli.timeout).await {
+                Ok(code) => std::process::exit(code),
+                Err(e) => ("Check failed", Err(e)),
+            }
+        }
+        Command::Logs { lines, follow } => ("Logs failed", logging::show_logs(lines, follow).await),
+        Command::Creds { action, network } => (
+            "Credentials failed",
+            creds(action, network.as_deref(), cli.console_level).await,
+        ),
+        Command::Diagnose { output, lines } => (
+            "Diagnose failed",
+            diagnose::run(output, lines, cli.timeout).await,
+        ),
+        Command::TestPortal { file, url } => {
+            ("Portal test failed", test_portal(&file, url.as_deref()))
+        }
+        Command::Logout => ("Logout failed", logout().await),
+        Command::TestNotification => match Config::load() {
+            Ok(config) => {
+                let delivered = notifications::send_test_notification(&config).await;
+                std::process::exit(if delivered { 0 } else { 1 })
+            }
+            Err(e) => ("Test notification failed", Err(e)),
+        },
+        Command::Help => {
+            println!("{}", cli::USAGE);
+            ("", Ok(()))
+        }
+        Command::Version => {
+            println!("{}", acp_script::BUILD);
+            ("", Ok(()))
+        }
+        Command::PrintConfigSchema => (
+            "Failed to generate config schema",
+            config::schema_json().map(|schema| println!("{}", schema)),
+        ),
+    };
+
+    if let Err(e) = result {
+        eprintln!("{}: {}", context, e);
+        std::process::exit(e.exit_code());
     }
 }
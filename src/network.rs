@@ -0,0 +1,185 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::process::Command;
+
+/// What identifies the current network for credential profiles and
+/// `allowed_ssids`.
+#[derive(Debug, Default)]
+pub struct NetworkIdentity {
+    pub ssid: Option<String>,
+    pub gateway_mac: Option<String>,
+}
+
+impl NetworkIdentity {
+    pub fn current() -> Self {
+        let identity = Self {
+            ssid: current_ssid(),
+            gateway_mac: gateway_mac(),
+        };
+        log::debug!(
+            "Current network: SSID {}, gateway MAC {}",
+            identity.ssid.as_deref().unwrap_or("<unknown>"),
+            identity.gateway_mac.as_deref().unwrap_or("<unknown>")
+        );
+        identity
+    }
+
+    /// Names a profile or allowlist entry can match, most specific first: the
+    /// gateway MAC also tells apart wired networks and hidden or duplicate
+    /// SSIDs.
+    pub fn names(&self) -> Vec<&str> {
+        self.gateway_mac
+            .iter()
+            .chain(&self.ssid)
+            .map(String::as_str)
+            .collect()
+    }
+}
+
+/// Parses a MAC address written as `aa:bb:cc:dd:ee:ff`, `AA-BB-CC-DD-EE-FF` or
+/// with single-digit octets as macOS prints them.
+pub fn parse_mac(text: &str) -> Option<String> {
+    let octets = text
+        .trim()
+        .split([':', '-'])
+        .map(|octet| {
+            (1..=2)
+                .contains(&octet.len())
+                .then(|| u8::from_str_radix(octet, 16).ok())
+                .flatten()
+        })
+        .collect::<Option<Vec<u8>>>()?;
+    // Incomplete ARP entries show up as all zeros.
+    if octets.len() != 6 || octets.iter().all(|&octet| octet == 0) {
+        return None;
+    }
+    Some(
+        octets
+            .iter()
+            .map(|octet| format!("{:02x}", octet))
+            .collect::<Vec<_>>()
+            .join(":"),
+    )
+}
+
+/// MAC addresses are compared in one canonical form; SSIDs as they are.
+pub fn normalize_network_name(name: &str) -> String {
+    parse_mac(name).unwrap_or_else(|| name.to_string())
+}
+
+/// The local address used to reach the internet, preferring IPv4. Connecting
+/// a UDP socket only looks up the route; nothing is sent.
+pub fn local_address() -> Option<IpAddr> {
+    let targets = [
+        (
+            SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            SocketAddr::from((Ipv4Addr::new(192, 0, 2, 1), 53)),
+        ),
+        (
+            SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+            SocketAddr::from((Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1), 53)),
+        ),
+    ];
+    targets.iter().find_map(|(local, remote)| {
+        let socket = UdpSocket::bind(local).ok()?;
+        socket.connect(remote).ok()?;
+        let ip = socket.local_addr().ok()?.ip();
+        (!ip.is_unspecified() && !ip.is_loopback()).then_some(ip)
+    })
+}
+
+/// Whether this machine has an address with a route to the internet.
+pub fn has_usable_address() -> bool {
+    local_address().is_some()
+}
+
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(target_os = "macos")]
+pub fn current_ssid() -> Option<String> {
+    let output = command_output("networksetup", &["-getairportnetwork", "en0"])?;
+    output
+        .trim()
+        .strip_prefix("Current Wi-Fi Network: ")
+        .map(str::to_string)
+}
+
+#[cfg(target_os = "linux")]
+pub fn current_ssid() -> Option<String> {
+    if let Some(ssid) = command_output("iwgetid", &["-r"])
+        .map(|output| output.trim().to_string())
+        .filter(|ssid| !ssid.is_empty())
+    {
+        return Some(ssid);
+    }
+
+    command_output("nmcli", &["-t", "-f", "active,ssid", "dev", "wifi"])?
+        .lines()
+        .find_map(|line| line.strip_prefix("yes:"))
+        .filter(|ssid| !ssid.is_empty())
+        .map(str::to_string)
+}
+
+#[cfg(target_os = "windows")]
+pub fn current_ssid() -> Option<String> {
+    command_output("netsh", &["wlan", "show", "interfaces"])?
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(key, _)| key.trim() == "SSID")
+        .map(|(_, ssid)| ssid.trim().to_string())
+        .filter(|ssid| !ssid.is_empty())
+}
+
+#[cfg(target_os = "linux")]
+pub fn gateway_mac() -> Option<String> {
+    use std::net::Ipv4Addr;
+
+    let routes = std::fs::read_to_string("/proc/net/route").ok()?;
+    let gateway = routes.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.as_slice() {
+            [_, "00000000", gateway, ..] if *gateway != "00000000" => {
+                u32::from_str_radix(gateway, 16)
+                    .ok()
+                    .map(|gateway| Ipv4Addr::from(gateway.to_le_bytes()).to_string())
+            }
+            _ => None,
+        }
+    })?;
+
+    std::fs::read_to_string("/proc/net/arp")
+        .ok()?
+        .lines()
+        .skip(1)
+        .map(|line| line.split_whitespace().collect::<Vec<_>>())
+        .find(|fields| fields.first() == Some(&gateway.as_str()))
+        .and_then(|fields| parse_mac(fields.get(3)?))
+}
+
+#[cfg(target_os = "macos")]
+pub fn gateway_mac() -> Option<String> {
+    let gateway = command_output("route", &["-n", "get", "default"])?
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("gateway: "))
+        .map(str::to_string)?;
+    // ? (192.168.1.1) at 0:1a:2b:3c:4d:5e on en0 ifscope [ethernet]
+    let arp = command_output("arp", &["-n", &gateway])?;
+    let (_, rest) = arp.split_once(" at ")?;
+    parse_mac(rest.split_whitespace().next()?)
+}
+
+#[cfg(target_os = "windows")]
+pub fn gateway_mac() -> Option<String> {
+    let script = "$route = Get-NetRoute -DestinationPrefix 0.0.0.0/0 | Sort-Object RouteMetric | \
+                  Select-Object -First 1; \
+                  (Get-NetNeighbor -IPAddress $route.NextHop | Select-Object -First 1).LinkLayerAddress";
+    parse_mac(&command_output(
+        "powershell",
+        &["-NoProfile", "-NonInteractive", "-Command", script],
+    )?)
+}
@@ -1,10 +1,242 @@
-use notify_rust::Notification;
-
-pub async fn send_notification(message: &str) {
-    Notification::new()
-        .body(message)
-        .appname("Auto Captive Portal")
-        .timeout(5)
-        .show()
-        .ok();
+use crate::captive_portal::{self, HttpOptions};
+use crate::config::{Config, NotificationSinkKind};
+use crate::error::{AppError, Result};
+use crate::hooks::HookEvent;
+use serde::Serialize;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+static SINKS: OnceLock<Vec<Box<dyn NotificationSink>>> = OnceLock::new();
+
+#[derive(Clone, Serialize)]
+pub struct NotificationPayload {
+    pub event: HookEvent,
+    pub message: String,
+    pub timestamp: String,
+    /// Without the query, which carries the magic value.
+    pub portal_url: Option<String>,
+}
+
+pub trait NotificationSink: Send + Sync {
+    fn notify(&self, payload: &NotificationPayload);
+}
+
+pub struct DesktopSink {
+    timeout: Duration,
+    failure_timeout: Duration,
+}
+
+impl DesktopSink {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            timeout: Duration::from_secs(config.notification_timeout_secs),
+            failure_timeout: Duration::from_secs(config.notification_failure_timeout_secs),
+        }
+    }
+}
+
+impl DesktopSink {
+    /// Shows the notification, failing when no notification service accepts it.
+    pub fn show(&self, payload: &NotificationPayload) -> Result<()> {
+        let mut notification = notify_rust::Notification::new();
+        notification
+            .body(&payload.message)
+            .appname("Auto Captive Portal");
+        // A zero duration becomes Timeout::Never, keeping it until dismissed.
+        match payload.event {
+            HookEvent::Login | HookEvent::Test => notification
+                .icon("dialog-information")
+                .timeout(self.timeout),
+            HookEvent::Failure | HookEvent::FailureAlert => notification
+                .icon("dialog-error")
+                .timeout(self.failure_timeout),
+        };
+        #[cfg(not(target_os = "macos"))]
+        notification.urgency(match payload.event {
+            HookEvent::Login | HookEvent::Test => notify_rust::Urgency::Normal,
+            HookEvent::Failure | HookEvent::FailureAlert => notify_rust::Urgency::Critical,
+        });
+        notification
+            .show()
+            .map(|_| ())
+            .map_err(|e| AppError::Notification(e.to_string()))
+    }
+}
+
+impl NotificationSink for DesktopSink {
+    fn notify(&self, payload: &NotificationPayload) {
+        self.show(payload).ok();
+    }
+}
+
+pub struct WebhookSink {
+    url: String,
+    client: reqwest::Client,
+}
+
+impl WebhookSink {
+    pub fn new(url: &str, http: &HttpOptions) -> Result<Self> {
+        let http = HttpOptions {
+            timeout: WEBHOOK_TIMEOUT,
+            ..http.clone()
+        };
+        Ok(Self {
+            url: url.to_string(),
+            client: captive_portal::build_http_client(&http)?,
+        })
+    }
+}
+
+impl WebhookSink {
+    /// Posts the notification and waits for the webhook to accept it.
+    pub async fn send(&self, payload: &NotificationPayload) -> Result<()> {
+        let status = self
+            .client
+            .post(&self.url)
+            .json(payload)
+            .send()
+            .await?
+            .status();
+        if !status.is_success() {
+            return Err(AppError::Notification(format!(
+                "webhook {} returned {}",
+                self.url, status
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl NotificationSink for WebhookSink {
+    fn notify(&self, payload: &NotificationPayload) {
+        let request = self.client.post(&self.url).json(payload);
+        let url = self.url.clone();
+        tokio::spawn(async move {
+            match request.send().await {
+                Ok(resp) if !resp.status().is_success() => {
+                    log::warn!("Notification webhook {} returned {}", url, resp.status());
+                }
+                Ok(_) => {}
+                Err(e) => log::warn!("Notification webhook {} failed: {}", url, e),
+            }
+        });
+    }
+}
+
+fn build_sinks(config: &Config) -> Vec<Box<dyn NotificationSink>> {
+    let mut sinks: Vec<Box<dyn NotificationSink>> = Vec::new();
+    if !config.notifications_enabled() {
+        return sinks;
+    }
+
+    let kind = config.notification_sink;
+    if matches!(
+        kind,
+        NotificationSinkKind::Desktop | NotificationSinkKind::Both
+    ) {
+        sinks.push(Box::new(DesktopSink::new(config)));
+    }
+    if matches!(
+        kind,
+        NotificationSinkKind::Webhook | NotificationSinkKind::Both
+    ) {
+        match config
+            .notification_webhook_url
+            .as_deref()
+            .map(|url| WebhookSink::new(url, &config.http_options()))
+        {
+            Some(Ok(sink)) => sinks.push(Box::new(sink)),
+            Some(Err(e)) => log::error!("Notification webhook setup failed: {}", e),
+            None => log::warn!(
+                "notification_sink uses a webhook but notification_webhook_url is not set"
+            ),
+        }
+    }
+    sinks
+}
+
+pub fn init(config: &Config) {
+    let _ = SINKS.set(build_sinks(config));
+}
+
+/// Sends a sample notification through each sink the config enables and
+/// waits for it, unlike the daemon's fire-and-forget notifications, so
+/// `test-notification` can report whether it got through. Returns whether
+/// every sink accepted it.
+pub async fn send_test_notification(config: &Config) -> bool {
+    if !config.notifications_enabled() {
+        println!(
+            "Notifications are disabled by notifications_enabled = false or ACP_NOTIFICATIONS=0"
+        );
+        return true;
+    }
+
+    let payload = NotificationPayload {
+        event: HookEvent::Test,
+        message: "Test notification from Auto Captive Portal".to_string(),
+        timestamp: chrono::Utc::now().to_rfc3339(),
+        portal_url: None,
+    };
+    let kind = config.notification_sink;
+    let mut failed = false;
+    if matches!(
+        kind,
+        NotificationSinkKind::Desktop | NotificationSinkKind::Both
+    ) {
+        match DesktopSink::new(config).show(&payload) {
+            Ok(()) => println!("Desktop: notification sent; check that it appeared"),
+            Err(e) => {
+                failed = true;
+                println!("Desktop: {}", e);
+                println!(
+                    "No notification service accepted it. On Linux this needs a D-Bus session \
+                     and a notification daemon, such as the one your desktop provides or dunst. \
+                     On headless machines set notifications_enabled = false, or use \
+                     notification_sink = \"webhook\"."
+                );
+            }
+        }
+    }
+    if matches!(
+        kind,
+        NotificationSinkKind::Webhook | NotificationSinkKind::Both
+    ) {
+        let result = match config.notification_webhook_url.as_deref() {
+            Some(url) => match WebhookSink::new(url, &config.http_options()) {
+                Ok(sink) => sink.send(&payload).await.map(|()| url),
+                Err(e) => Err(e),
+            },
+            None => Err(AppError::Config(
+                "notification_sink uses a webhook but notification_webhook_url is not set".into(),
+            )),
+        };
+        match result {
+            Ok(url) => println!("Webhook: delivered to {}", url),
+            Err(e) => {
+                failed = true;
+                println!("Webhook: {}", e);
+            }
+        }
+    }
+    !failed
+}
+
+pub fn send_notification(event: HookEvent, message: &str, portal_url: Option<&str>) {
+    let sinks = SINKS.get_or_init(|| vec![Box::new(DesktopSink::new(&Config::default()))]);
+    if sinks.is_empty() {
+        log::info!("Notification: {}", message);
+        return;
+    }
+
+    let payload = NotificationPayload {
+        event,
+        message: message.to_string(),
+        timestamp: chrono::Utc::now().to_rfc3339(),
+        portal_url: portal_url.map(captive_portal::shareable_portal_url),
+    };
+    for sink in sinks {
+        sink.notify(&payload);
+    }
 }
@@ -1,39 +1,136 @@
 use crate::error::{AppError, Result};
-use keyring::Entry;
+use secrecy::zeroize::Zeroize;
+use secrecy::{ExposeSecret, SecretString};
+#[cfg(target_os = "windows")]
+use std::ffi::OsString;
 use std::fs;
+use std::io::Write;
 use std::path::PathBuf;
 
-pub const SERVICE_NAME: &str = if cfg!(target_os = "macos") {
-    "com.user.acp"
-} else {
-    "acp"
-};
+pub use crate::SERVICE_NAME;
+
+/// Where `setup --system` stores the credentials, as ACP_USERNAME and
+/// ACP_PASSWORD for the unit's `EnvironmentFile=`: a system service has no
+/// user keyring to read them from.
+pub const SYSTEM_CREDENTIALS_FILE: &str = "/etc/acp/credentials.env";
+
+/// The launchd domain of the current user's login session, `gui/<uid>`.
+#[cfg(target_os = "macos")]
+fn launchd_domain() -> Result<String> {
+    let output = std::process::Command::new("id").arg("-u").output()?;
+    let uid = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if !output.status.success() || uid.is_empty() {
+        return Err(AppError::ServiceControl {
+            command: "id -u",
+            message: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        });
+    }
+    Ok(format!("gui/{}", uid))
+}
+
+/// The service in its launchd domain, `gui/<uid>/<label>`.
+#[cfg(target_os = "macos")]
+fn launchd_service_target() -> Result<String> {
+    Ok(format!("{}/{}", launchd_domain()?, SERVICE_NAME))
+}
+
+#[cfg(target_os = "macos")]
+fn service_file_path() -> Result<PathBuf> {
+    Ok(dirs::home_dir()
+        .ok_or(AppError::HomeDirNotFound)?
+        .join("Library/LaunchAgents")
+        .join(format!("{}.plist", SERVICE_NAME)))
+}
+
+#[cfg(target_os = "linux")]
+fn user_service_file_path() -> Result<PathBuf> {
+    Ok(dirs::home_dir()
+        .ok_or(AppError::HomeDirNotFound)?
+        .join(".config/systemd/user")
+        .join(format!("{}.service", SERVICE_NAME)))
+}
+
+#[cfg(target_os = "linux")]
+fn system_service_file_path() -> PathBuf {
+    PathBuf::from("/etc/systemd/system").join(format!("{}.service", SERVICE_NAME))
+}
+
+#[cfg(target_os = "linux")]
+fn is_system_service() -> bool {
+    system_service_file_path().exists()
+}
+
+/// The installed unit, preferring a system-wide one.
+#[cfg(target_os = "linux")]
+fn service_file_path() -> Result<PathBuf> {
+    if is_system_service() {
+        Ok(system_service_file_path())
+    } else {
+        user_service_file_path()
+    }
+}
+
+/// `systemctl` for the system manager, or for the user's with `--user`.
+#[cfg(target_os = "linux")]
+fn systemctl(system: bool) -> std::process::Command {
+    let mut command = std::process::Command::new("systemctl");
+    if !system {
+        command.arg("--user");
+    }
+    command
+}
+
+/// Whether the process runs as root: `/proc/self` belongs to the effective
+/// user.
+#[cfg(target_os = "linux")]
+fn is_root() -> bool {
+    use std::os::unix::fs::MetadataExt;
+    fs::metadata("/proc/self").is_ok_and(|metadata| metadata.uid() == 0)
+}
 
 pub struct ServiceManager {
     executable_path: PathBuf,
+    system: bool,
 }
 
 impl ServiceManager {
     pub fn new(executable_path: PathBuf) -> Self {
-        Self { executable_path }
+        Self {
+            executable_path,
+            system: false,
+        }
     }
 
-    pub fn store_credentials(&self, username: &str, password: &str) -> Result<()> {
-        let username_entry = Entry::new(SERVICE_NAME, "ldap_username")?;
-        username_entry.set_password(username)?;
-
-        let password_entry = Entry::new(SERVICE_NAME, "ldap_password")?;
-        password_entry.set_password(password)?;
+    /// Installs a system-wide service that starts at boot, instead of one
+    /// that runs while the current user is logged in. Linux only.
+    pub fn with_system_scope(mut self, system: bool) -> Self {
+        self.system = system;
+        self
+    }
 
-        Ok(())
+    /// Fails early when the service can't be installed with the current
+    /// privileges, before anything is prompted for or written.
+    pub fn check_privileges(&self) -> Result<()> {
+        if !self.system {
+            return Ok(());
+        }
+        #[cfg(target_os = "linux")]
+        if is_root() {
+            return Ok(());
+        }
+        Err(AppError::ServiceControl {
+            command: "setup --system",
+            message: if cfg!(target_os = "linux") {
+                "installing a system-wide service requires root; run it with sudo".into()
+            } else {
+                "system-wide installation is only supported on Linux".into()
+            },
+        })
     }
 
     #[cfg(target_os = "macos")]
     pub fn create_service(&self) -> Result<()> {
-        let plist_path = dirs::home_dir()
-            .ok_or_else(|| AppError::Service("Home directory not found".into()))?
-            .join("Library/LaunchAgents")
-            .join(format!("{}.plist", SERVICE_NAME));
+        let plist_path = service_file_path()?;
 
         fs::create_dir_all(plist_path.parent().unwrap())?;
 
@@ -60,84 +157,503 @@ impl ServiceManager {
 
         fs::write(&plist_path, plist_content)?;
 
-        let output = std::process::Command::new("launchctl")
-            .args(["load", plist_path.to_str().unwrap()])
-            .output()?;
-
-        if !output.status.success() {
-            return Err(AppError::Service(
-                String::from_utf8_lossy(&output.stderr).into_owned(),
-            ));
-        }
-
         Ok(())
     }
 
     #[cfg(target_os = "linux")]
-    pub fn create_service(&self) -> io::Result<()> {
+    pub fn create_service(&self) -> Result<()> {
         let service_name = SERVICE_NAME;
-        let service_dir = dirs::home_dir().unwrap().join(".config/systemd/user");
+        let service_path = if self.system {
+            system_service_file_path()
+        } else {
+            user_service_file_path()?
+        };
+
+        fs::create_dir_all(service_path.parent().unwrap())?;
+
+        let service_content = if self.system {
+            format!(
+                r#"[Unit]
+Description=Auto Captive Portal Login Service
+Wants=network-online.target
+After=network-online.target
 
-        fs::create_dir_all(&service_dir)?;
+[Service]
+ExecStart={}
+EnvironmentFile={}
+Restart=always
+RestartSec=10
 
-        let service_content = format!(
-            r#"[Unit]
+[Install]
+WantedBy=multi-user.target"#,
+                self.executable_path.display(),
+                SYSTEM_CREDENTIALS_FILE
+            )
+        } else {
+            format!(
+                r#"[Unit]
 Description=Auto Captive Portal Login Service
 
 [Service]
 ExecStart={}
-Restart=on-failure
+Restart=always
 RestartSec=10
 
 [Install]
 WantedBy=default.target"#,
-            self.executable_path.display()
-        );
+                self.executable_path.display()
+            )
+        };
 
-        let service_path = service_dir.join(format!("{}.service", service_name));
         fs::write(&service_path, service_content)?;
 
-        std::process::Command::new("systemctl")
-            .args(["--user", "daemon-reload"])
+        systemctl(self.system).arg("daemon-reload").output()?;
+
+        systemctl(self.system)
+            .args(["enable", service_name])
             .output()?;
 
-        std::process::Command::new("systemctl")
-            .args(["--user", "enable", service_name])
+        Ok(())
+    }
+
+    #[cfg(target_os = "windows")]
+    pub fn create_service(&self) -> Result<()> {
+        use windows_service::service::{
+            ServiceAccess, ServiceErrorControl, ServiceInfo, ServiceStartType, ServiceType,
+        };
+        use windows_service::service_manager::{
+            ServiceManager as WindowsServiceManager, ServiceManagerAccess,
+        };
+
+        let manager = WindowsServiceManager::local_computer(
+            None::<&str>,
+            ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE,
+        )
+        .map_err(service_error)?;
+
+        let service_info = ServiceInfo {
+            name: OsString::from(SERVICE_NAME),
+            display_name: OsString::from("Auto Captive Portal"),
+            service_type: ServiceType::OWN_PROCESS,
+            start_type: ServiceStartType::AutoStart,
+            error_control: ServiceErrorControl::Normal,
+            executable_path: self.executable_path.clone(),
+            launch_arguments: vec![OsString::from("service")],
+            dependencies: vec![],
+            account_name: None,
+            account_password: None,
+        };
+
+        let access = ServiceAccess::CHANGE_CONFIG | ServiceAccess::START;
+        let service = match manager.create_service(&service_info, access) {
+            Ok(service) => service,
+            // Re-running setup updates the existing service instead.
+            Err(windows_service::Error::Winapi(e))
+                if e.raw_os_error() == Some(ERROR_SERVICE_EXISTS) =>
+            {
+                let service = manager
+                    .open_service(SERVICE_NAME, access)
+                    .map_err(service_error)?;
+                service
+                    .change_config(&service_info)
+                    .map_err(service_error)?;
+                service
+            }
+            Err(e) => return Err(service_error(e)),
+        };
+        service
+            .set_description("Automatically logs in to captive portals")
+            .map_err(service_error)?;
+
+        Ok(())
+    }
+}
+
+/// Starts the service installed by `create_service`. A service left running
+/// by an earlier setup is restarted, so a reinstall takes effect.
+pub fn start_service() -> Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        let output = systemctl(is_system_service())
+            .args(["restart", SERVICE_NAME])
             .output()?;
 
-        std::process::Command::new("systemctl")
-            .args(["--user", "start", service_name])
+        if !output.status.success() {
+            return Err(AppError::ServiceControl {
+                command: "systemctl restart",
+                message: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            });
+        }
+    }
+
+    #[cfg(target_os = "macos")]
+    {
+        let plist_path = service_file_path()?;
+        let target = launchd_service_target()?;
+        // Bootstrapping an agent that is already loaded fails, and would keep
+        // the old plist anyway; booting out one that isn't loaded is harmless.
+        std::process::Command::new("launchctl")
+            .args(["bootout", &target])
+            .output()?;
+        let output = std::process::Command::new("launchctl")
+            .args([
+                "bootstrap",
+                &launchd_domain()?,
+                plist_path.to_str().unwrap(),
+            ])
             .output()?;
 
-        Ok(())
+        // bootout can return before the old instance is gone, and bootstrap
+        // then fails even though the agent ends up loaded.
+        let loaded = || {
+            std::process::Command::new("launchctl")
+                .args(["print", &target])
+                .output()
+                .is_ok_and(|output| output.status.success())
+        };
+        if !output.status.success() && !loaded() {
+            return Err(AppError::ServiceControl {
+                command: "launchctl bootstrap",
+                message: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            });
+        }
+    }
+
+    #[cfg(target_os = "windows")]
+    {
+        use windows_service::service::ServiceAccess;
+        use windows_service::service_manager::{
+            ServiceManager as WindowsServiceManager, ServiceManagerAccess,
+        };
+
+        match WindowsServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)
+            .and_then(|manager| manager.open_service(SERVICE_NAME, ServiceAccess::START))
+            .and_then(|service| service.start(&[] as &[&std::ffi::OsStr]))
+        {
+            Err(windows_service::Error::Winapi(e))
+                if e.raw_os_error() == Some(ERROR_SERVICE_ALREADY_RUNNING) => {}
+            result => result.map_err(service_error)?,
+        }
+    }
+
+    Ok(())
+}
+
+/// The shell command that does what `start_service` does.
+pub fn start_command() -> Result<String> {
+    #[cfg(target_os = "linux")]
+    let command = match is_system_service() {
+        true => format!("sudo systemctl start {}", SERVICE_NAME),
+        false => format!("systemctl --user start {}", SERVICE_NAME),
+    };
+    #[cfg(target_os = "macos")]
+    let command = format!(
+        "launchctl bootstrap {} {}",
+        launchd_domain()?,
+        service_file_path()?.display()
+    );
+    #[cfg(target_os = "windows")]
+    let command = format!("sc.exe start {}", SERVICE_NAME);
+
+    Ok(command)
+}
+
+/// Writes the credentials to `SYSTEM_CREDENTIALS_FILE`, readable only by its
+/// owner.
+pub fn store_system_credentials(username: &str, password: &SecretString) -> Result<PathBuf> {
+    let path = PathBuf::from(SYSTEM_CREDENTIALS_FILE);
+    fs::create_dir_all(path.parent().unwrap())?;
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(&path)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    }
+
+    // systemd strips the double quotes and their backslash escapes.
+    let quote = |value: &str| format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""));
+    let mut contents = format!(
+        "ACP_USERNAME={}\nACP_PASSWORD={}\n",
+        quote(username),
+        quote(password.expose_secret())
+    );
+    let result = file.write_all(contents.as_bytes());
+    contents.zeroize();
+    result?;
+    Ok(path)
+}
+
+#[cfg(target_os = "windows")]
+const ERROR_SERVICE_DOES_NOT_EXIST: i32 = 1060;
+
+#[cfg(target_os = "windows")]
+const ERROR_SERVICE_EXISTS: i32 = 1073;
+
+#[cfg(target_os = "windows")]
+const ERROR_SERVICE_ALREADY_RUNNING: i32 = 1056;
+
+#[cfg(target_os = "windows")]
+const WINDOWS_STOP_TIMEOUT_SECS: u32 = 30;
+
+#[cfg(target_os = "windows")]
+fn service_error(e: windows_service::Error) -> AppError {
+    AppError::ServiceControl {
+        command: "Windows service manager",
+        message: e.to_string(),
+    }
+}
+
+#[cfg(target_os = "windows")]
+windows_service::define_windows_service!(ffi_service_main, windows_service_main);
+
+#[cfg(target_os = "windows")]
+pub fn run_as_windows_service() -> Result<()> {
+    windows_service::service_dispatcher::start(SERVICE_NAME, ffi_service_main)
+        .map_err(service_error)
+}
+
+#[cfg(target_os = "windows")]
+fn windows_service_main(_arguments: Vec<OsString>) {
+    if let Err(e) = run_windows_service() {
+        log::error!("Service error: {}", e);
+    }
+}
+
+#[cfg(target_os = "windows")]
+fn run_windows_service() -> Result<()> {
+    use std::time::Duration;
+    use windows_service::service::{
+        ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceState, ServiceStatus,
+        ServiceType,
+    };
+    use windows_service::service_control_handler::{self, ServiceControlHandlerResult};
+
+    let (shutdown_tx, mut shutdown_rx) = tokio::sync::mpsc::unbounded_channel();
+    let status_handle =
+        service_control_handler::register(SERVICE_NAME, move |control| match control {
+            ServiceControl::Stop | ServiceControl::Shutdown => {
+                let _ = shutdown_tx.send(());
+                ServiceControlHandlerResult::NoError
+            }
+            ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+            _ => ServiceControlHandlerResult::NotImplemented,
+        })
+        .map_err(service_error)?;
+
+    let set_state = |current_state: ServiceState, controls_accepted: ServiceControlAccept| {
+        status_handle
+            .set_service_status(ServiceStatus {
+                service_type: ServiceType::OWN_PROCESS,
+                current_state,
+                controls_accepted,
+                exit_code: ServiceExitCode::Win32(0),
+                checkpoint: 0,
+                wait_hint: Duration::default(),
+                process_id: None,
+            })
+            .map_err(service_error)
+    };
+
+    set_state(
+        ServiceState::Running,
+        ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+    )?;
+
+    let runtime = tokio::runtime::Runtime::new()?;
+    let result = runtime.block_on(crate::daemon::run_until(None, async move {
+        shutdown_rx.recv().await;
+    }));
+
+    set_state(ServiceState::Stopped, ServiceControlAccept::empty())?;
+    result
+}
+
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+pub fn remove_service() -> Result<()> {
+    let service_path = service_file_path()?;
+
+    #[cfg(target_os = "macos")]
+    if service_path.exists() {
+        std::process::Command::new("launchctl")
+            .args(["bootout", &launchd_service_target()?])
+            .output()?;
+    }
+
+    #[cfg(target_os = "linux")]
+    let system = is_system_service();
+    #[cfg(target_os = "linux")]
+    systemctl(system)
+        .args(["disable", "--now", SERVICE_NAME])
+        .output()?;
+
+    if service_path.exists() {
+        fs::remove_file(&service_path)?;
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        systemctl(system).arg("daemon-reload").output()?;
+        if system && std::path::Path::new(SYSTEM_CREDENTIALS_FILE).exists() {
+            fs::remove_file(SYSTEM_CREDENTIALS_FILE)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(target_os = "windows")]
+pub fn remove_service() -> Result<()> {
+    use windows_service::service::{ServiceAccess, ServiceState};
+    use windows_service::service_manager::{
+        ServiceManager as WindowsServiceManager, ServiceManagerAccess,
+    };
+
+    let manager =
+        WindowsServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)
+            .map_err(service_error)?;
+    let service = match manager.open_service(
+        SERVICE_NAME,
+        ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE,
+    ) {
+        Ok(service) => service,
+        Err(windows_service::Error::Winapi(e))
+            if e.raw_os_error() == Some(ERROR_SERVICE_DOES_NOT_EXIST) =>
+        {
+            return Ok(())
+        }
+        Err(e) => return Err(service_error(e)),
+    };
+
+    if service.query_status().map_err(service_error)?.current_state != ServiceState::Stopped {
+        service.stop().map_err(service_error)?;
     }
+    service.delete().map_err(service_error)
+}
+
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+pub fn is_service_installed() -> bool {
+    service_file_path().is_ok_and(|path| path.exists())
+}
+
+#[cfg(target_os = "windows")]
+pub fn is_service_installed() -> bool {
+    use windows_service::service::ServiceAccess;
+    use windows_service::service_manager::{
+        ServiceManager as WindowsServiceManager, ServiceManagerAccess,
+    };
+
+    WindowsServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)
+        .and_then(|manager| manager.open_service(SERVICE_NAME, ServiceAccess::QUERY_STATUS))
+        .is_ok()
+}
+
+#[cfg(target_os = "linux")]
+pub fn is_service_running() -> bool {
+    systemctl(is_system_service())
+        .args(["is-active", "--quiet", SERVICE_NAME])
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+#[cfg(target_os = "macos")]
+pub fn is_service_running() -> bool {
+    let Ok(target) = launchd_service_target() else {
+        return false;
+    };
+    std::process::Command::new("launchctl")
+        .args(["print", &target])
+        .output()
+        .is_ok_and(|output| {
+            output.status.success()
+                && String::from_utf8_lossy(&output.stdout).contains("state = running")
+        })
+}
+
+#[cfg(target_os = "windows")]
+pub fn is_service_running() -> bool {
+    use windows_service::service::{ServiceAccess, ServiceState};
+    use windows_service::service_manager::{
+        ServiceManager as WindowsServiceManager, ServiceManagerAccess,
+    };
+
+    WindowsServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)
+        .and_then(|manager| manager.open_service(SERVICE_NAME, ServiceAccess::QUERY_STATUS))
+        .and_then(|service| service.query_status())
+        .is_ok_and(|status| status.current_state == ServiceState::Running)
 }
 
 pub async fn restart_service() -> Result<()> {
     #[cfg(target_os = "linux")]
     {
-        let output = std::process::Command::new("systemctl")
-            .args(["--user", "restart", SERVICE_NAME])
+        let output = systemctl(is_system_service())
+            .args(["restart", SERVICE_NAME])
             .output()?;
 
         if !output.status.success() {
-            return Err(AppError::Service(
-                String::from_utf8_lossy(&output.stderr).into_owned(),
-            ));
+            return Err(AppError::ServiceControl {
+                command: "systemctl restart",
+                message: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            });
         }
     }
 
     #[cfg(target_os = "macos")]
     {
         let output = std::process::Command::new("launchctl")
-            .args(["kickstart", "-k", SERVICE_NAME])
+            .args(["kickstart", "-k", &launchd_service_target()?])
             .output()?;
 
         if !output.status.success() {
-            return Err(AppError::Service(
-                String::from_utf8_lossy(&output.stderr).into_owned(),
-            ));
+            return Err(AppError::ServiceControl {
+                command: "launchctl kickstart",
+                message: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            });
+        }
+    }
+
+    #[cfg(target_os = "windows")]
+    {
+        use windows_service::service::{ServiceAccess, ServiceState};
+        use windows_service::service_manager::{
+            ServiceManager as WindowsServiceManager, ServiceManagerAccess,
+        };
+
+        let manager =
+            WindowsServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)
+                .map_err(service_error)?;
+        let service = manager
+            .open_service(
+                SERVICE_NAME,
+                ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::START,
+            )
+            .map_err(service_error)?;
+
+        if service.query_status().map_err(service_error)?.current_state != ServiceState::Stopped {
+            service.stop().map_err(service_error)?;
+        }
+        let mut waited = 0;
+        while service.query_status().map_err(service_error)?.current_state != ServiceState::Stopped
+        {
+            if waited >= WINDOWS_STOP_TIMEOUT_SECS {
+                return Err(AppError::ServiceControl {
+                    command: "Windows service manager",
+                    message: "timed out waiting for the service to stop".into(),
+                });
+            }
+            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+            waited += 1;
         }
+        service
+            .start(&[] as &[&std::ffi::OsStr])
+            .map_err(service_error)?;
     }
 
     Ok(())
@@ -0,0 +1,413 @@
+use crate::captive_portal::{Portal, PortalKind};
+use crate::error::{AppError, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ServiceState {
+    pub last_check: Option<DateTime<Utc>>,
+    pub last_login: Option<DateTime<Utc>>,
+    pub last_portal_detected: Option<String>,
+    pub last_portal_kind: Option<PortalKind>,
+    pub last_login_ssid: Option<String>,
+    /// Layout hash of the login page at the last successful form login.
+    pub last_portal_html_hash: Option<String>,
+    pub last_probe: Option<ProbeResult>,
+    pub next_check: Option<NextCheck>,
+    pub credential_networks: Vec<String>,
+    pub total_checks: u64,
+    pub total_logins: u64,
+    pub total_failures: u64,
+    pub consecutive_failures: u64,
+    /// Times of the latest failures in the current streak, at most as many as
+    /// the alert threshold.
+    pub failure_streak: Vec<DateTime<Utc>>,
+    /// Whether the current failure streak already raised its alert.
+    pub failure_alert_sent: bool,
+    /// Checks in a row whose login the portal rejected for bad credentials.
+    pub credential_rejections: u32,
+    /// No logins are attempted until the credentials change or a check is
+    /// requested.
+    pub logins_paused: bool,
+    /// Login pages of portals logged in to before, by gateway MAC or SSID.
+    pub portal_cache: BTreeMap<String, CachedPortal>,
+}
+
+/// A portal's login page as last seen on a network, so a reconnect can log in
+/// without following the portal's redirects again.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CachedPortal {
+    /// Where the connectivity probe was sent when the login page was cached.
+    pub detected_url: String,
+    /// The login page, after following redirects. Its query carries the magic
+    /// value.
+    pub url: String,
+    pub kind: PortalKind,
+    pub cached_at: DateTime<Utc>,
+}
+
+/// Outcome of the daemon's latest portal detection, for `status` to reuse.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProbeResult {
+    pub checked_at: DateTime<Utc>,
+    pub portal_url: Option<String>,
+    pub portal_kind: Option<PortalKind>,
+}
+
+/// When the daemon plans its next check, for `status` to show.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NextCheck {
+    pub at: DateTime<Utc>,
+    pub delay_secs: u64,
+    /// Whether the delay comes from the retry backoff after a failed or offline
+    /// check.
+    pub retrying: bool,
+}
+
+impl ProbeResult {
+    pub fn portal(&self) -> Option<Portal> {
+        Some(Portal::new(self.portal_url.clone()?, self.portal_kind?))
+    }
+}
+
+impl CachedPortal {
+    pub fn portal(&self) -> Portal {
+        Portal::new(self.url.clone(), self.kind)
+    }
+
+    /// Whether `detected` redirected to the same host as when this was cached,
+    /// so the cached page still belongs to the portal in front of the network.
+    pub fn matches(&self, detected: &Portal) -> bool {
+        let host = |url: &str| {
+            reqwest::Url::parse(url)
+                .ok()?
+                .host_str()
+                .map(str::to_string)
+        };
+        host(&self.detected_url).is_some_and(|cached| Some(cached) == host(detected.url()))
+    }
+}
+
+impl ServiceState {
+    pub fn record_check(&mut self) {
+        self.last_check = Some(Utc::now());
+        self.total_checks += 1;
+    }
+
+    pub fn record_login(&mut self, portal: &Portal, ssid: Option<&str>) {
+        self.last_login = Some(Utc::now());
+        self.last_login_ssid = ssid.map(str::to_string);
+        self.last_portal_detected = Some(portal.url().to_string());
+        self.last_portal_kind = Some(portal.kind());
+        self.total_logins += 1;
+        self.end_failure_streak();
+        self.credential_rejections = 0;
+    }
+
+    /// Counts a rejected login and pauses logins once `limit` are reached in a
+    /// row. Returns whether this rejection paused them.
+    pub fn record_credential_rejection(&mut self, limit: u32) -> bool {
+        self.credential_rejections += 1;
+        let pause = limit > 0 && self.credential_rejections >= limit && !self.logins_paused;
+        self.logins_paused |= pause;
+        pause
+    }
+
+    /// Remembers `login_page` as the login page of the portal detected at
+    /// `detected` on the network named `network`.
+    pub fn cache_portal(&mut self, network: &str, detected: &Portal, login_page: &Portal) {
+        self.portal_cache.insert(
+            network.to_string(),
+            CachedPortal {
+                detected_url: detected.url().to_string(),
+                url: login_page.url().to_string(),
+                kind: login_page.kind(),
+                cached_at: Utc::now(),
+            },
+        );
+    }
+
+    pub fn resume_logins(&mut self) {
+        self.credential_rejections = 0;
+        self.logins_paused = false;
+    }
+
+    pub fn record_probe(&mut self, portal: Option<&Portal>) {
+        self.last_probe = Some(ProbeResult {
+            checked_at: Utc::now(),
+            portal_url: portal.map(|portal| portal.url().to_string()),
+            portal_kind: portal.map(Portal::kind),
+        });
+    }
+
+    pub fn schedule_check(&mut self, delay: Duration, retrying: bool) {
+        self.next_check = Some(NextCheck {
+            at: Utc::now() + delay,
+            delay_secs: delay.as_secs(),
+            retrying,
+        });
+    }
+
+    /// The latest probe result, if it is at most `max_age` old.
+    pub fn recent_probe(&self, max_age: Duration) -> Option<&ProbeResult> {
+        self.last_probe.as_ref().filter(|probe| {
+            (Utc::now() - probe.checked_at)
+                .to_std()
+                .is_ok_and(|age| age <= max_age)
+        })
+    }
+
+    /// Counts a failed check. Returns whether it completes `threshold`
+    /// failures in a row within `window` (unlimited when zero), which alerts
+    /// once per streak.
+    pub fn record_failure(&mut self, threshold: u32, window: Duration) -> bool {
+        self.total_failures += 1;
+        self.consecutive_failures += 1;
+
+        let now = Utc::now();
+        self.failure_streak.push(now);
+        let excess = self
+            .failure_streak
+            .len()
+            .saturating_sub(threshold.max(1) as usize);
+        self.failure_streak.drain(..excess);
+
+        let within_window = window.is_zero()
+            || (now - self.failure_streak[0])
+                .to_std()
+                .is_ok_and(|span| span <= window);
+        let alert = threshold > 0
+            && !self.failure_alert_sent
+            && self.failure_streak.len() >= threshold as usize
+            && within_window;
+        self.failure_alert_sent |= alert;
+        alert
+    }
+
+    /// Resets the failure streak after a check that succeeded.
+    pub fn end_failure_streak(&mut self) {
+        self.consecutive_failures = 0;
+        self.failure_streak.clear();
+        self.failure_alert_sent = false;
+    }
+
+    /// Whether the daemon logged in to a portal on this Wi-Fi network before,
+    /// so a passing probe means the session is still active.
+    pub fn logged_in_on(&self, ssid: Option<&str>) -> bool {
+        self.last_login.is_some() && ssid.is_some() && self.last_login_ssid.as_deref() == ssid
+    }
+
+    pub fn success_rate(&self) -> Option<f64> {
+        let attempts = self.total_logins + self.total_failures;
+        (attempts > 0).then(|| self.total_logins as f64 / attempts as f64)
+    }
+}
+
+/// Per-user directory for state, events and logs. On Windows this is the
+/// local (non-roaming) app data, so users of a shared machine never collide.
+pub fn data_dir() -> Result<PathBuf> {
+    let data_dir = if cfg!(target_os = "windows") {
+        std::env::var_os("LOCALAPPDATA")
+            .map(PathBuf::from)
+            .or_else(dirs::data_local_dir)
+            .ok_or(AppError::HomeDirNotFound)?
+            .join("acp")
+    } else {
+        dirs::home_dir()
+            .ok_or(AppError::HomeDirNotFound)?
+            .join(".local/share/acp")
+    };
+
+    Ok(data_dir)
+}
+
+pub fn get_state_file_path() -> Result<PathBuf> {
+    Ok(data_dir()?.join("state.json"))
+}
+
+pub fn load_state() -> Result<ServiceState> {
+    let state_path = get_state_file_path()?;
+    if !state_path.exists() {
+        return Ok(ServiceState::default());
+    }
+
+    let contents = fs::read_to_string(&state_path)?;
+    match serde_json::from_str(&contents) {
+        Ok(state) => Ok(state),
+        Err(e) => {
+            log::warn!(
+                "State file {} is corrupt ({}), starting from an empty state",
+                state_path.display(),
+                e
+            );
+            Ok(ServiceState::default())
+        }
+    }
+}
+
+/// Writes through a temporary file in the same directory and renames it into
+/// place, so a crash mid-write never leaves a truncated file behind.
+pub(crate) fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+    replace_file(path, contents, false)
+}
+
+/// [`write_atomically`] for files only the user may read (mode 0600 on Unix).
+pub(crate) fn write_private_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+    replace_file(path, contents, true)
+}
+
+fn replace_file(path: &Path, contents: &[u8], private: bool) -> io::Result<()> {
+    let mut temp_name = path.as_os_str().to_owned();
+    temp_name.push(format!(".{}.tmp", std::process::id()));
+    let temp_path = PathBuf::from(temp_name);
+
+    let result = create_temp_file(&temp_path, private)
+        .and_then(|mut file| {
+            file.write_all(contents)?;
+            file.sync_all()
+        })
+        .and_then(|()| fs::rename(&temp_path, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result
+}
+
+fn create_temp_file(path: &Path, private: bool) -> io::Result<fs::File> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    if private {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        options.mode(0o600);
+        let file = options.open(path)?;
+        // The mode only applies on creation; tighten a leftover temporary file too.
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        return Ok(file);
+    }
+    #[cfg(not(unix))]
+    let _ = private;
+    options.open(path)
+}
+
+fn lock_file_path(state_path: &Path) -> PathBuf {
+    let mut name = state_path.as_os_str().to_owned();
+    name.push(".lock");
+    PathBuf::from(name)
+}
+
+/// Loads, updates and saves the state while holding an exclusive lock, so the
+/// daemon and a concurrent `check` or `creds` never lose each other's updates.
+pub fn update_state_file(update: impl FnOnce(&mut ServiceState)) -> Result<()> {
+    let state_path = get_state_file_path()?;
+    if let Some(parent) = state_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    // The state file is replaced by a rename, so the lock lives in a file of
+    // its own. It is released when `lock` is dropped.
+    let lock_path = lock_file_path(&state_path);
+    let lock = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_path)?;
+    lock.lock().map_err(|e| AppError::StatePersistence {
+        path: lock_path,
+        message: format!("failed to lock: {}", e),
+    })?;
+
+    let mut state = load_state()?;
+    update(&mut state);
+
+    let contents =
+        serde_json::to_string_pretty(&state).map_err(|e| AppError::StatePersistence {
+            path: state_path.clone(),
+            message: format!("failed to serialize: {}", e),
+        })?;
+    write_atomically(&state_path, contents.as_bytes())?;
+
+    Ok(())
+}
+
+pub fn remove_state_file() -> Result<()> {
+    let state_path = get_state_file_path()?;
+    for path in [lock_file_path(&state_path), state_path] {
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn portal() -> Portal {
+        Portal::new(
+            "http://10.0.0.1:1000/fgtauth?0a1b2c",
+            PortalKind::HttpRedirect,
+        )
+    }
+
+    #[test]
+    fn a_login_ends_the_failure_streak_but_keeps_the_totals() {
+        let mut state = ServiceState::default();
+        assert!(!state.record_failure(0, Duration::ZERO));
+        assert!(!state.record_failure(0, Duration::ZERO));
+        assert_eq!(state.consecutive_failures, 2);
+
+        state.record_login(&portal(), Some("Office"));
+        assert_eq!(state.consecutive_failures, 0);
+        assert!(state.failure_streak.is_empty());
+        assert_eq!(state.total_failures, 2);
+        assert_eq!(state.total_logins, 1);
+        assert_eq!(state.success_rate(), Some(1.0 / 3.0));
+    }
+
+    #[test]
+    fn an_online_check_ends_the_failure_streak() {
+        let mut state = ServiceState::default();
+        state.record_failure(0, Duration::ZERO);
+        state.end_failure_streak();
+        assert_eq!(state.consecutive_failures, 0);
+        assert_eq!(state.total_failures, 1);
+    }
+
+    #[test]
+    fn alerts_once_per_failure_streak() {
+        let mut state = ServiceState::default();
+        assert!(!state.record_failure(2, Duration::ZERO));
+        assert!(state.record_failure(2, Duration::ZERO));
+        assert!(!state.record_failure(2, Duration::ZERO));
+
+        state.record_login(&portal(), None);
+        assert!(!state.record_failure(2, Duration::ZERO));
+        assert!(state.record_failure(2, Duration::ZERO));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn private_files_are_replaced_with_mode_0600() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("credentials.json");
+        fs::write(&path, "old").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+
+        write_private_atomically(&path, b"new").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}
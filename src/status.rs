@@ -0,0 +1,283 @@
+use crate::captive_portal::{self, PortalKind};
+use crate::config::Config;
+use crate::credentials::{self, CredentialSource};
+use crate::error::Result;
+use crate::events::{self, Event};
+use crate::network::NetworkIdentity;
+use crate::service;
+use crate::state::{self, NextCheck};
+use chrono::{DateTime, Local, Utc};
+use serde::Serialize;
+use std::fmt;
+use std::io::{self, Write};
+use std::time::Duration;
+use tokio::time::Instant;
+
+#[derive(Default, Serialize)]
+pub struct StatusReport {
+    pub credentials_configured: bool,
+    pub credential_source: Option<CredentialSource>,
+    pub service_running: bool,
+    pub internet_connected: bool,
+    pub ipv4_connected: bool,
+    pub ipv6_connected: Option<bool>,
+    pub portal_detected: bool,
+    pub already_logged_in: bool,
+    pub portal_url: Option<String>,
+    pub portal_kind: Option<PortalKind>,
+    pub last_check: Option<DateTime<Utc>>,
+    pub next_check: Option<NextCheck>,
+    pub last_login: Option<DateTime<Utc>>,
+    pub last_portal_detected: Option<String>,
+    pub last_portal_kind: Option<PortalKind>,
+    pub total_checks: u64,
+    pub total_logins: u64,
+    pub total_failures: u64,
+    pub consecutive_failures: u64,
+    pub logins_paused: bool,
+    pub success_rate: Option<f64>,
+    pub recent_events: Vec<Event>,
+}
+
+const RECENT_EVENTS_SHOWN: usize = 10;
+// A portal check by the daemon this recent is reused instead of probing again.
+const PROBE_CACHE_MAX_AGE: Duration = Duration::from_secs(5);
+
+pub(crate) async fn collect(config: &Config) -> Result<StatusReport> {
+    let clients = config.http_clients()?;
+    let state = state::load_state()?;
+    let network = NetworkIdentity::current();
+    let ssid = network.ssid.as_deref();
+    let portal = match state.recent_probe(PROBE_CACHE_MAX_AGE) {
+        Some(probe) => {
+            log::debug!(
+                "Reusing the daemon's portal check from {}",
+                probe.checked_at
+            );
+            probe.portal()
+        }
+        None => captive_portal::detect_with_probes(
+            &config.connectivity_probes(),
+            &config.http_options(),
+            &clients.probe,
+        )
+        .await
+        .ok()
+        .flatten(),
+    };
+    let connectivity =
+        captive_portal::check_connectivity(&config.connectivity_probe, &config.http_options())
+            .await?;
+
+    let credential_source = credentials::credential_source(&network.names()).ok();
+    let service_running = service::is_service_running();
+
+    Ok(StatusReport {
+        credentials_configured: credential_source.is_some(),
+        credential_source,
+        service_running,
+        internet_connected: connectivity.v4 || connectivity.v6,
+        ipv4_connected: connectivity.v4,
+        ipv6_connected: config
+            .connectivity_probe
+            .check_ipv6
+            .then_some(connectivity.v6),
+        portal_detected: portal.is_some(),
+        already_logged_in: portal.is_none()
+            && (connectivity.v4 || connectivity.v6)
+            && state.logged_in_on(ssid),
+        portal_url: portal.as_ref().map(|portal| portal.url().to_string()),
+        portal_kind: portal.as_ref().map(|portal| portal.kind()),
+        success_rate: state.success_rate(),
+        last_check: state.last_check,
+        // Left over from a daemon that is no longer running.
+        next_check: state.next_check.filter(|_| service_running),
+        last_login: state.last_login,
+        last_portal_detected: state.last_portal_detected,
+        last_portal_kind: state.last_portal_kind,
+        total_checks: state.total_checks,
+        total_logins: state.total_logins,
+        total_failures: state.total_failures,
+        consecutive_failures: state.consecutive_failures,
+        logins_paused: state.logins_paused,
+        recent_events: events::recent_events(RECENT_EVENTS_SHOWN),
+    })
+}
+
+fn format_time(time: Option<DateTime<Utc>>) -> String {
+    time.map(|time| {
+        time.with_timezone(&Local)
+            .format("%Y-%m-%d %H:%M:%S")
+            .to_string()
+    })
+    .unwrap_or_else(|| "never".to_string())
+}
+
+fn format_next_check(next: &NextCheck) -> String {
+    let secs = (next.at - Utc::now()).num_seconds();
+    let when = match secs {
+        ..=0 => "due now".to_string(),
+        1..=89 => format!("in ~{}s", secs),
+        _ => format!("in ~{} minutes", (secs + 30) / 60),
+    };
+    if next.retrying {
+        format!("{} (retrying after a failed check)", when)
+    } else {
+        when
+    }
+}
+
+fn with_kind(url: &str, kind: Option<PortalKind>) -> String {
+    match kind {
+        Some(kind) => format!("{} ({})", url, kind),
+        None => url.to_string(),
+    }
+}
+
+fn yes_no(value: bool, yes: &str, no: &str) -> String {
+    if value { yes } else { no }.to_string()
+}
+
+/// The report as `status` prints it, for the terminal and the status page.
+pub(crate) fn format_report(report: &StatusReport) -> String {
+    let mut out = String::new();
+    // Writing to a String never fails.
+    let _ = write_report(&mut out, report);
+    out
+}
+
+fn write_report(out: &mut impl fmt::Write, report: &StatusReport) -> fmt::Result {
+    let portal = match &report.portal_url {
+        Some(url) => format!("detected at {}", with_kind(url, report.portal_kind)),
+        None if report.already_logged_in => "already logged in".to_string(),
+        None => "none detected".to_string(),
+    };
+
+    writeln!(out, "Auto Captive Portal status")?;
+    let credentials = match report.credential_source {
+        Some(source) => format!("configured ({})", source),
+        None => "missing".to_string(),
+    };
+    writeln!(out, "  Credentials:     {}", credentials)?;
+    writeln!(
+        out,
+        "  Service:         {}",
+        yes_no(report.service_running, "running", "not running")
+    )?;
+    let ipv6 = match report.ipv6_connected {
+        Some(connected) => yes_no(connected, "connected", "not connected"),
+        None => "not checked".to_string(),
+    };
+    writeln!(
+        out,
+        "  Internet:        IPv4 {}, IPv6 {}",
+        yes_no(report.ipv4_connected, "connected", "not connected"),
+        ipv6
+    )?;
+    writeln!(out, "  Captive portal:  {}", portal)?;
+    writeln!(out, "  Last check:      {}", format_time(report.last_check))?;
+    if let Some(next) = &report.next_check {
+        writeln!(out, "  Next check:      {}", format_next_check(next))?;
+    }
+    writeln!(out, "  Last login:      {}", format_time(report.last_login))?;
+    if let Some(url) = &report.last_portal_detected {
+        writeln!(
+            out,
+            "  Last portal:     {}",
+            with_kind(url, report.last_portal_kind)
+        )?;
+    }
+    writeln!(out, "  Checks:          {}", report.total_checks)?;
+    writeln!(
+        out,
+        "  Logins:          {} ({} failures, {} in a row)",
+        report.total_logins, report.total_failures, report.consecutive_failures
+    )?;
+    if report.logins_paused {
+        writeln!(out, "  Logins paused:   the portal kept rejecting the credentials; run `acp-script creds set`")?;
+    }
+    if let Some(rate) = report.success_rate {
+        writeln!(out, "  Success rate:    {:.1}%", rate * 100.0)?;
+    }
+    if !report.recent_events.is_empty() {
+        writeln!(out, "  Recent events:")?;
+        for event in &report.recent_events {
+            let time = format_time(Some(event.timestamp));
+            match &event.detail {
+                Some(detail) => writeln!(out, "    {}  {}: {}", time, event.kind, detail),
+                None => writeln!(out, "    {}  {}", time, event.kind),
+            }?;
+        }
+    }
+    Ok(())
+}
+
+fn print_report(report: &StatusReport) {
+    print!("{}", format_report(report));
+}
+
+pub async fn show_status(json: bool, timeout: Option<u64>) -> Result<()> {
+    let config = Config::load()?.with_request_timeout(timeout);
+    let report = collect(&config).await?;
+
+    if json {
+        let output = serde_json::to_string_pretty(&report)?;
+        println!("{}", output);
+    } else {
+        print_report(&report);
+    }
+    Ok(())
+}
+
+/// Clears the terminal and prints the report, for `watch_status`.
+fn redraw(report: &StatusReport, interval: Duration) -> Result<()> {
+    print!("\x1b[H\x1b[2J");
+    print_report(report);
+    println!();
+    println!(
+        "Refreshing every {}s; press Ctrl-C to stop",
+        interval.as_secs()
+    );
+    io::stdout().flush()?;
+    Ok(())
+}
+
+/// Redraws the status every `interval` until Ctrl-C, as a live dashboard.
+/// A terminal resize redraws the current report right away.
+pub async fn watch_status(interval: Duration, timeout: Option<u64>) -> Result<()> {
+    let config = Config::load()?.with_request_timeout(timeout);
+    #[cfg(unix)]
+    let mut resizes =
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::window_change())?;
+    let stop = tokio::signal::ctrl_c();
+    tokio::pin!(stop);
+
+    // Hide the cursor while redrawing, and show it again however the loop ends.
+    print!("\x1b[?25l");
+    let result = async {
+        loop {
+            let report = tokio::select! {
+                report = collect(&config) => report?,
+                _ = &mut stop => return Ok(()),
+            };
+            redraw(&report, interval)?;
+
+            let next = Instant::now() + interval;
+            loop {
+                #[cfg(unix)]
+                let resized = resizes.recv();
+                #[cfg(not(unix))]
+                let resized = std::future::pending::<Option<()>>();
+                tokio::select! {
+                    _ = tokio::time::sleep_until(next) => break,
+                    _ = resized => redraw(&report, interval)?,
+                    _ = &mut stop => return Ok(()),
+                }
+            }
+        }
+    }
+    .await;
+    print!("\x1b[?25h");
+    io::stdout().flush()?;
+    result
+}
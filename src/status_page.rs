@@ -0,0 +1,268 @@
+//! A minimal local web page with the same summary as `acp-script status`,
+//! for kiosks where staff glance at a screen instead of a terminal.
+
+use crate::config::Config;
+use crate::error::Result;
+use crate::status::{self, StatusReport};
+use chrono::Local;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+
+// Browsers send a few hundred bytes; anything longer isn't a status request.
+const MAX_REQUEST_BYTES: usize = 8192;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const MIN_REFRESH: Duration = Duration::from_secs(1);
+
+/// The last rendered page, shared by every viewer until it is `refresh` old,
+/// so several screens don't each trigger connectivity checks.
+type PageCache = Arc<Mutex<Option<(Instant, String)>>>;
+
+/// Serves the status page on `listen` until the daemon stops. Logs and gives
+/// up when the address can't be bound, without affecting the daemon.
+pub async fn serve(listen: String, refresh: Duration) {
+    // Viewers wait behind a render, so never probe more than once a second.
+    let refresh = refresh.max(MIN_REFRESH);
+    let listener = match TcpListener::bind(&listen).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            log::error!("Status page disabled: cannot listen on {}: {}", listen, e);
+            return;
+        }
+    };
+    match listener.local_addr() {
+        Ok(addr) if !addr.ip().is_loopback() => log::warn!(
+            "Status page listening on {}, reachable from the network; it shows portal URLs",
+            addr
+        ),
+        Ok(addr) => log::info!("Status page at http://{}/", addr),
+        Err(_) => {}
+    }
+
+    let cache = PageCache::default();
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let cache = cache.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = handle(stream, cache, refresh).await {
+                        log::debug!("Status page request failed: {}", e);
+                    }
+                });
+            }
+            Err(e) => {
+                log::warn!("Status page failed to accept a connection: {}", e);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        }
+    }
+}
+
+async fn handle(mut stream: TcpStream, cache: PageCache, refresh: Duration) -> Result<()> {
+    let Ok(request) = tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await else {
+        return Ok(());
+    };
+    let request = request?;
+    let mut request_line = request.split_whitespace();
+    let response = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some("/")) => response(
+            "200 OK",
+            "text/html; charset=utf-8",
+            &page(&cache, refresh).await,
+        ),
+        (Some("GET"), Some(_)) => response("404 Not Found", "text/plain", "Not found\n"),
+        _ => response(
+            "405 Method Not Allowed",
+            "text/plain",
+            "Only GET is supported\n",
+        ),
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Reads the request line and headers, which is all a status request has.
+async fn read_request(stream: &mut TcpStream) -> Result<String> {
+    let mut request = Vec::new();
+    let mut buffer = [0; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n")
+        && request.len() < MAX_REQUEST_BYTES
+    {
+        let read = stream.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        request.extend_from_slice(&buffer[..read]);
+    }
+    Ok(String::from_utf8_lossy(&request).into_owned())
+}
+
+fn response(status: &str, content_type: &str, body: &str) -> String {
+    format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\n\
+         Connection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )
+}
+
+async fn page(cache: &PageCache, refresh: Duration) -> String {
+    let mut cache = cache.lock().await;
+    if let Some((rendered_at, page)) = cache.as_ref() {
+        if rendered_at.elapsed() < refresh {
+            return page.clone();
+        }
+    }
+
+    // The config is read for every render, like `status` does, so edits show up.
+    let report = match Config::load() {
+        Ok(config) => status::collect(&config).await,
+        Err(e) => Err(e),
+    };
+    let page = match report {
+        Ok(report) => render(&report, refresh),
+        Err(e) => render_error(&e.to_string(), refresh),
+    };
+    *cache = Some((Instant::now(), page.clone()));
+    page
+}
+
+/// The one-line verdict shown in large type, with its colour.
+fn headline(report: &StatusReport) -> (&'static str, &'static str) {
+    if report.logins_paused {
+        ("Logins paused: check the credentials", "#b91c1c")
+    } else if report.portal_detected {
+        ("Captive portal detected, not logged in", "#b45309")
+    } else if report.internet_connected {
+        ("Online", "#15803d")
+    } else {
+        ("Offline", "#b91c1c")
+    }
+}
+
+fn render(report: &StatusReport, refresh: Duration) -> String {
+    let (headline, colour) = headline(report);
+    document(
+        refresh,
+        &format!(
+            "<h1 style=\"color:{}\">{}</h1>\n<pre>{}</pre>",
+            colour,
+            headline,
+            escape(&status::format_report(report))
+        ),
+    )
+}
+
+fn render_error(error: &str, refresh: Duration) -> String {
+    document(
+        refresh,
+        &format!(
+            "<h1 style=\"color:#b91c1c\">Status unavailable</h1>\n<pre>{}</pre>",
+            escape(error)
+        ),
+    )
+}
+
+fn document(refresh: Duration, body: &str) -> String {
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+         <meta http-equiv=\"refresh\" content=\"{refresh}\">\n\
+         <title>Auto Captive Portal</title>\n\
+         <style>body{{font-family:sans-serif;margin:2em}}h1{{font-size:2.5em}}\
+         pre{{font-size:1.1em}}footer{{color:#6b7280}}</style>\n</head>\n<body>\n{body}\n\
+         <footer>Updated {updated}; refreshes every {refresh}s</footer>\n</body>\n</html>\n",
+        refresh = refresh.as_secs(),
+        body = body,
+        updated = Local::now().format("%Y-%m-%d %H:%M:%S"),
+    )
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_html() {
+        assert_eq!(
+            escape(r#"<a href="x">Tom & Jerry</a>"#),
+            "&lt;a href=&quot;x&quot;&gt;Tom &amp; Jerry&lt;/a&gt;"
+        );
+    }
+
+    #[test]
+    fn headline_puts_paused_logins_first() {
+        let mut report = StatusReport::default();
+        assert_eq!(headline(&report).0, "Offline");
+        report.internet_connected = true;
+        assert_eq!(headline(&report).0, "Online");
+        report.portal_detected = true;
+        assert_eq!(
+            headline(&report).0,
+            "Captive portal detected, not logged in"
+        );
+        report.logins_paused = true;
+        assert_eq!(headline(&report).0, "Logins paused: check the credentials");
+    }
+
+    /// Sends `request` to `handle` over loopback and returns the response.
+    async fn roundtrip(request: &str, cache: PageCache) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        let handler = tokio::spawn(handle(server, cache, Duration::from_secs(60)));
+
+        client.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        handler.await.unwrap().unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn routes_requests() {
+        // A fresh page in the cache keeps the test from probing the network.
+        let cache = PageCache::default();
+        *cache.lock().await = Some((Instant::now(), "<p>cached</p>".to_string()));
+
+        let response = roundtrip("GET / HTTP/1.1\r\nHost: x\r\n\r\n", cache.clone()).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains("Content-Type: text/html; charset=utf-8\r\n"));
+        assert!(response.ends_with("\r\n\r\n<p>cached</p>"), "{}", response);
+
+        let response = roundtrip("GET /favicon.ico HTTP/1.1\r\n\r\n", cache.clone()).await;
+        assert!(
+            response.starts_with("HTTP/1.1 404 Not Found\r\n"),
+            "{}",
+            response
+        );
+
+        let response = roundtrip("POST / HTTP/1.1\r\n\r\n", cache).await;
+        assert!(
+            response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"),
+            "{}",
+            response
+        );
+    }
+
+    #[test]
+    fn document_refreshes_at_the_given_period() {
+        let page = document(Duration::from_secs(7), "<p>body</p>");
+        assert!(page.contains(r#"<meta http-equiv="refresh" content="7">"#));
+        assert!(page.contains("refreshes every 7s"));
+    }
+}